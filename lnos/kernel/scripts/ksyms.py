@@ -0,0 +1,90 @@
+#!/usr/bin/env python3
+"""将kernel的符号表写入kernel ELF的.ksyms段
+
+.ksyms段在kernel中预留（见src/arch/x86_64/backtrace.rs），链接后由本脚本原地写入，
+kernel在panic和Exception时使用该符号表将地址解析为函数名。
+
+符号表格式（小端）：
+- 头部：magic（b"KSYM"）、符号数（u32）、字符串表大小（u64）；
+- 按地址排序的符号：地址（u64）、大小（u64）、名称在字符串表中的偏移（u32）、名称长度（u32）；
+- 字符串表：所有符号的名称（demangle后，不以0结尾）。
+
+用法：ksyms.py <kernel elf>，可以通过环境变量NM指定nm程序。
+"""
+
+import os
+import struct
+import subprocess
+import sys
+
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sIQ")
+ENTRY = struct.Struct("<QQII")
+SHDR = struct.Struct("<IIQQQQIIQQ")
+
+
+def find_section(data, name):
+    """返回ELF64中名为name的段在文件中的偏移和大小"""
+    if data[:4] != b"\x7fELF" or data[4] != 2:
+        sys.exit("ksyms: not an ELF64 file")
+    shoff, = struct.unpack_from("<Q", data, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", data, 0x3a)
+    strtab = SHDR.unpack_from(data, shoff + shstrndx * shentsize)
+    for k in range(shnum):
+        sh = SHDR.unpack_from(data, shoff + k * shentsize)
+        start = strtab[4] + sh[0]
+        if data[start:data.index(b"\0", start)].decode() == name:
+            return sh[4], sh[5]
+    return None
+
+
+def read_symbols(path):
+    """使用nm读取代码段中的符号，按地址排序"""
+    nm = os.environ.get("NM", "nm")
+    out = subprocess.run([nm, "--defined-only", "-n", "-S", "-C", path],
+                         check=True, capture_output=True, text=True).stdout
+    symbols = []
+    for line in out.splitlines():
+        fields = line.split(maxsplit=3)
+        if len(fields) == 4:
+            addr, size, kind, name = fields
+        elif len(fields) == 3:
+            (addr, kind, name), size = fields, "0"
+        else:
+            continue
+        if kind in "tTwW":
+            symbols.append((int(addr, 16), int(size, 16), name))
+    return symbols
+
+
+def build(symbols, limit):
+    entries, strtab = [], bytearray()
+    for addr, size, name in symbols:
+        raw = name.encode()
+        if HEADER.size + (len(entries) + 1) * ENTRY.size + len(strtab) + len(raw) > limit:
+            print("ksyms: .ksyms is full, %d symbols dropped" % (len(symbols) - len(entries)), file=sys.stderr)
+            break
+        entries.append(ENTRY.pack(addr, size, len(strtab), len(raw)))
+        strtab += raw
+    blob = HEADER.pack(MAGIC, len(entries), len(strtab)) + b"".join(entries) + strtab
+    return blob + bytes(limit - len(blob))
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: ksyms.py <kernel elf>")
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        data = f.read()
+    section = find_section(data, ".ksyms")
+    if section is None:
+        sys.exit("ksyms: no .ksyms section in %s" % path)
+    offset, size = section
+    blob = build(read_symbols(path), size)
+    with open(path, "r+b") as f:
+        f.seek(offset)
+        f.write(blob)
+
+
+if __name__ == "__main__":
+    main()
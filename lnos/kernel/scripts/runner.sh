@@ -0,0 +1,8 @@
+#!/bin/sh
+# cargo run/test的runner
+#
+# 先将符号表写入kernel的.ksyms段，再使用bootimage生成镜像并在qemu中运行。
+
+set -e
+python3 "$(dirname "$0")/ksyms.py" "$1"
+exec bootimage runner "$@"
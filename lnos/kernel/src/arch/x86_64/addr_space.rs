@@ -0,0 +1,290 @@
+//! 地址空间模块
+//!
+//! 每个AddressSpace有独立的L4页表：
+//! - 用户空间[USER_START, USER_END)由各AddressSpace独立管理；
+//! - 内核空间（L4[0]和L4[256..512)）直接复制内核L4中对应的Entry，所有AddressSpace共享下级页表。
+//!
+//! 内核线程可以通过kthread::set_address_space绑定AddressSpace，调度器切换线程时会切换CR3。
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+use super::memory::{kernel_l4, phys_to_virt, GlobalFrameAllocator, PageTableImpl, KERNEL_L4_START};
+use super::usermode::{USER_END, USER_START};
+
+
+/// 由AddressSpace::map分配的Frame（使用页表Entry中可供系统使用的BIT_9标记），在unmap或drop时释放
+const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// 用户空间的中间页表的flags
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// 地址空间操作错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// 没有可用的物理内存
+    OutOfMemory,
+    /// 地址不在用户空间中
+    NotUser,
+    /// page已经映射
+    AlreadyMapped,
+    /// page没有映射
+    NotMapped,
+}
+
+/// 地址空间
+pub struct AddressSpace {
+    l4: PhysFrame,
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// 检查地址范围[start, start + len)是否位于用户空间，返回范围的结束地址
+fn check_user(start: VirtAddr, len: usize) -> Result<u64, MapError> {
+    match start.as_u64().checked_add(len as u64) {
+        Some(end) if start.as_u64() >= USER_START as u64 && end <= USER_END as u64 => Ok(end),
+        _ => Err(MapError::NotUser),
+    }
+}
+
+/// 地址范围[start, start + len)包含的Page，且需要位于用户空间
+fn user_pages(start: VirtAddr, len: usize) -> Result<impl Iterator<Item = Page>, MapError> {
+    let end = check_user(start, len)?;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let count = if len == 0 { 0 } else { (end - first.start_address().as_u64() + Size4KiB::SIZE - 1) / Size4KiB::SIZE };
+    Ok((0 .. count).map(move |k| first + k))
+}
+
+impl AddressSpace {
+    /// 创建一个新的地址空间，只包含内核空间的映射
+    pub fn new() -> Option<Self> {
+        let l4 = GlobalFrameAllocator.allocate_frame()?;
+        unsafe {
+            let table = table_mut(l4);
+            let kernel = table_mut(kernel_l4());
+            table.zero();
+            table[0] = kernel[0].clone();
+            for k in KERNEL_L4_START .. 512 {
+                table[k] = kernel[k].clone();
+            }
+        }
+        Some(AddressSpace { l4 })
+    }
+
+    /// L4页表的物理Frame
+    pub fn l4_frame(&self) -> PhysFrame {
+        self.l4
+    }
+
+    fn page_table(&mut self) -> PageTableImpl {
+        unsafe { PageTableImpl::new(self.l4) }
+    }
+
+    /// 映射[start, start + len)，并为每个page分配清零的Frame
+    pub fn map(&mut self, start: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), MapError> {
+        for page in user_pages(start, len)? {
+            let frame = GlobalFrameAllocator.allocate_frame().ok_or(MapError::OutOfMemory)?;
+            unsafe {
+                core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+            }
+            if let Err(err) = self.map_to(page, frame, flags | OWNED) {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// 将page映射到指定的frame（frame不属于该地址空间，unmap和drop时不释放）
+    pub fn map_frame(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+        check_user(page.start_address(), 1)?;
+        self.map_to(page, frame, flags & !OWNED)
+    }
+
+    /// 只修改用户空间的页表（由&mut self保证独占），不需要持有内核页表的锁
+    fn map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+        use x86_64::structures::paging::mapper::MapToError;
+        let mut pg = self.page_table();
+        let result = unsafe {
+            pg.mapper.map_to_with_table_flags(page, frame, flags | PageTableFlags::PRESENT, TABLE_FLAGS, &mut GlobalFrameAllocator)
+        };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            },
+            Err(MapToError::FrameAllocationFailed) => Err(MapError::OutOfMemory),
+            Err(_) => Err(MapError::AlreadyMapped),
+        }
+    }
+
+    /// 取消映射[start, start + len)，未映射的page直接跳过（整个范围只进行一次TLB shootdown）
+    pub fn unmap(&mut self, start: VirtAddr, len: usize) -> Result<(), MapError> {
+        check_user(start, len)?;
+        for (phys, flags, _) in self.page_table().unmap_range(start, len as u64) {
+            if flags.contains(OWNED) {
+                unsafe { GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)) };
+            }
+        }
+        Ok(())
+    }
+
+    /// 修改[start, start + len)的flags，所有page都需要已经映射（整个范围只进行一次TLB shootdown）
+    pub fn protect(&mut self, start: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), MapError> {
+        let mut result = Ok(());
+        for page in user_pages(start, len)? {
+            let owned = match self.translate(page.start_address()) {
+                Some((_, f)) => f & OWNED,
+                None => {
+                    result = Err(MapError::NotMapped);
+                    break;
+                },
+            };
+            let mut pg = self.page_table();
+            if !pg.update_flags_local(page, flags | owned | PageTableFlags::PRESENT) {
+                result = Err(MapError::NotMapped);
+                break;
+            }
+        }
+        // 失败时已经修改的部分同样需要刷新
+        super::memory::flush_tlb_range(start, len as u64);
+        result
+    }
+
+    /// 虚拟地址对应的物理地址和flags
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.page_table().mapper.translate(addr) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                let base = match frame {
+                    MappedFrame::Size4KiB(f) => f.start_address(),
+                    MappedFrame::Size2MiB(f) => f.start_address(),
+                    MappedFrame::Size1GiB(f) => f.start_address(),
+                };
+                Some((base + offset, flags))
+            },
+            _ => None,
+        }
+    }
+
+    /// 向地址空间写入数据（不需要激活该地址空间），地址需要已经映射
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        let mut done = 0;
+        while done < data.len() {
+            let virt = addr + done;
+            let (phys, _) = self.translate(virt).ok_or(MapError::NotMapped)?;
+            let len = (Size4KiB::SIZE - virt.as_u64() % Size4KiB::SIZE).min((data.len() - done) as u64) as usize;
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done ..].as_ptr(), phys_to_virt(phys).as_mut_ptr::<u8>(), len);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// 是否为当前CPU使用的地址空间
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4
+    }
+
+    /// 切换到该地址空间
+    pub fn activate(&self) {
+        switch_l4(self.l4);
+    }
+}
+
+/// 切换到内核的地址空间
+pub fn activate_kernel() {
+    switch_l4(kernel_l4());
+}
+
+fn switch_l4(l4: PhysFrame) {
+    let (cur, flags) = Cr3::read();
+    if cur != l4 {
+        // 内核空间的映射在所有地址空间中相同，切换后可以继续执行
+        unsafe { Cr3::write(l4, flags) };
+    }
+}
+
+impl Drop for AddressSpace {
+    /// 释放用户空间中所有OWNED的Frame和中间页表，最后释放L4
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+        let mut fa = GlobalFrameAllocator;
+        unsafe {
+            let l4 = table_mut(self.l4);
+            for l4e in l4.iter().skip(1).take(KERNEL_L4_START - 1).filter(|e| !e.is_unused()) {
+                let l3_frame = PhysFrame::containing_address(l4e.addr());
+                for l3e in table_mut(l3_frame).iter().filter(|e| !e.is_unused()) {
+                    if l3e.flags().contains(PageTableFlags::HUGE_PAGE) {
+                        continue;
+                    }
+                    let l2_frame = PhysFrame::containing_address(l3e.addr());
+                    for l2e in table_mut(l2_frame).iter().filter(|e| !e.is_unused()) {
+                        if l2e.flags().contains(PageTableFlags::HUGE_PAGE) {
+                            continue;
+                        }
+                        let l1_frame = PhysFrame::containing_address(l2e.addr());
+                        for l1e in table_mut(l1_frame).iter().filter(|e| e.flags().contains(OWNED)) {
+                            fa.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(l1e.addr()));
+                        }
+                        fa.deallocate_frame(l1_frame);
+                    }
+                    fa.deallocate_frame(l2_frame);
+                }
+                fa.deallocate_frame(l3_frame);
+            }
+            fa.deallocate_frame(self.l4);
+        }
+    }
+}
+
+
+
+/// 两个地址空间在同一虚拟地址映射不同的数据
+#[test_case]
+fn test_address_space() {
+    use alloc::sync::Arc;
+    const ADDR: u64 = 0x0000_1000_0000_0000;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let handles = [1u64, 2u64].map(|value| {
+        let mut space = AddressSpace::new().expect("out of memory");
+        space.map(VirtAddr::new(ADDR), 0x2000, flags).unwrap();
+        assert_eq!(space.map(VirtAddr::new(ADDR), 0x1000, flags), Err(MapError::AlreadyMapped));
+        space.write(VirtAddr::new(ADDR + 0xff8), &value.to_le_bytes()).unwrap();
+        space.write(VirtAddr::new(ADDR + 0x1000), &(value * 10).to_le_bytes()).unwrap();
+        let space = Arc::new(space);
+        crate::kthread::spawn("addr_space", move || {
+            crate::kthread::set_address_space(Some(space));
+            crate::kthread::yield_now();
+            unsafe { *((ADDR + 0xff8) as *const u64) + *((ADDR + 0x1000) as *const u64) }
+        })
+    });
+    let [h1, h2] = handles;
+    assert_eq!(h1.join(), Some(11));
+    assert_eq!(h2.join(), Some(22));
+
+    // 内核的地址空间中没有该映射
+    let pg = PageTableImpl::active();
+    assert!(pg.mapper.translate_addr(VirtAddr::new(ADDR)).is_none());
+
+    let mut space = AddressSpace::new().expect("out of memory");
+    space.map(VirtAddr::new(ADDR), 0x1000, flags).unwrap();
+    space.protect(VirtAddr::new(ADDR), 0x1000, flags & !PageTableFlags::WRITABLE).unwrap();
+    assert!(!space.translate(VirtAddr::new(ADDR)).unwrap().1.contains(PageTableFlags::WRITABLE));
+    space.unmap(VirtAddr::new(ADDR), 0x1000).unwrap();
+    assert!(space.translate(VirtAddr::new(ADDR)).is_none());
+    assert_eq!(space.map(VirtAddr::new(0x1000), 0x1000, flags), Err(MapError::NotUser));
+}
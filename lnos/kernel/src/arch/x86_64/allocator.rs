@@ -2,22 +2,33 @@
 //!
 //! 堆内存的分配，可以基于rust的堆内存管理来实现。
 //! rust的alloc库的堆分配器需要实现GlobalAlloc。
-//! 实现一个简单的Fixed Size Block Allocator。
+//! 不大于1024字节的内存由Slab分配器（见slab模块）按大小分类分配，其余的由fallback（链表分配器）分配。
+//!
+//! 堆的初始范围注册为VMA，只在第一次访问时才映射物理Frame；
+//! fallback分配失败时，从GlobalFrameAllocator申请Frame映射到堆顶之后，扩展堆的大小，
+//! 直到达到堆的上限（limit，可以通过set_limit修改，最大为HEAP_MAX_SIZE）。
+//! slab的Frame不占用堆的地址范围，但同样计入堆的大小，受上限限制。
 
-use super::memory::GlobalFrameAllocator;
-use super::memory::PageTableImpl;
+use super::memory::{GlobalFrameAllocator, PageTableImpl};
+use super::slab::{SlabCache, SlabStats};
+use super::vma::{self, VmaError};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::{mem, ptr::{null_mut, NonNull}};
+use core::ptr::{null_mut, NonNull};
 use x86_64::{
-    structures::paging::{*, mapper::MapToError},
+    instructions::interrupts,
+    structures::paging::*,
     VirtAddr,
 };
 
 
-/// 设置堆地址
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-/// 设置堆大小
-pub const HEAP_SIZE: usize = 100 * 1024;
+/// 设置堆地址（位于内核空间）
+pub const HEAP_START: usize = 0x_ffff_c000_0000_0000;
+/// 设置堆的初始大小（按需映射，只占用虚拟地址）
+pub const HEAP_SIZE: usize = 1024 * 1024;
+/// 堆可以增长到的最大大小（预留的虚拟地址范围）
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024;
+/// 堆每次增长的最小大小
+const HEAP_GROW_SIZE: usize = 64 * 1024;
 
 /// 设置alloc库的堆分配器
 #[global_allocator]
@@ -46,34 +57,56 @@ impl<T> GlobalHeapLocker<T> {
     }
 }
 
-/// 链表内存块的大小
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
-
-struct ListNode {
-    next: Option<&'static mut ListNode>,
-}
+/// Slab对象的大小
+pub const SLAB_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024];
 
-/// 从BLOCK_SIZES查找合适的内存块
-fn list_index(layout: &Layout) -> Option<usize> {
+/// 从SLAB_SIZES查找合适的SlabCache
+fn slab_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    SLAB_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
 /// kernel堆内存分配器
 ///
-/// list_heads是内存块的链表，内存块支持BLOCK_SIZES中的大小；
-/// 当list_heads不满足内存分配条件时，使用fallback来分配内存。
+/// caches是每种SLAB_SIZES大小的SlabCache；
+/// 当caches不满足内存分配条件时，使用fallback来分配内存。
 pub struct GlobalHeapAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    caches: [SlabCache; SLAB_SIZES.len()],
     fallback: linked_list_allocator::Heap,
+    /// 堆大小的上限
+    limit: usize,
+    /// 已分配给调用者的字节数（按内存块大小计算）
+    used: usize,
+    /// used的最大值
+    high_water: usize,
+}
+
+/// 堆的统计信息
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// 堆的当前大小（fallback的地址范围）
+    pub size: usize,
+    /// slab占用的Frame的字节数
+    pub slab: usize,
+    /// 已分配的字节数
+    pub used: usize,
+    /// 已分配字节数的最大值
+    pub high_water: usize,
+    /// 堆大小（size + slab）的上限
+    pub limit: usize,
 }
 
 impl GlobalHeapAllocator {
     pub const fn new() -> Self {
         Self {
-            //list_heads: [None; BLOCK_SIZES.len()],
-            list_heads: [None, None, None, None, None, None, None, None, None],
+            caches: [
+                SlabCache::new(8), SlabCache::new(16), SlabCache::new(32), SlabCache::new(64),
+                SlabCache::new(128), SlabCache::new(256), SlabCache::new(512), SlabCache::new(1024),
+            ],
             fallback: linked_list_allocator::Heap::empty(),
+            limit: HEAP_MAX_SIZE,
+            used: 0,
+            high_water: 0,
         }
     }
 
@@ -84,82 +117,189 @@ impl GlobalHeapAllocator {
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
+            Err(_) if self.grow(layout) => match self.fallback.allocate_first_fit(layout) {
+                Ok(ptr) => ptr.as_ptr(),
+                Err(_) => null_mut(),
+            },
             Err(_) => null_mut(),
         }
     }
+
+    /// 扩展堆，使其可以分配layout
+    ///
+    /// 新增的部分立即映射（而不是按需映射），按2MiB对齐的部分优先使用2MiB的page；
+    /// Frame（包括页表的Frame）不足时取消已经映射的部分并返回false，由调用者返回null。
+    fn grow(&mut self, layout: Layout) -> bool {
+        let need = layout.size() + layout.align();
+        let unit = if need >= Size2MiB::SIZE as usize { Size2MiB::SIZE as usize } else { HEAP_GROW_SIZE };
+        let by = (need + unit - 1) / unit * unit;
+        if self.footprint() + by > self.limit {
+            return false;
+        }
+
+        let top = self.fallback.top() as u64;
+        let end = top + by as u64;
+        let mut pg = PageTableImpl::active();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut addr = top;
+        while addr < end {
+            let virt = VirtAddr::new(addr);
+            if virt.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+                let frame: Option<PhysFrame<Size2MiB>> = GlobalFrameAllocator.allocate_frame();
+                if let Some(frame) = frame {
+                    if pg.try_map(Page::containing_address(virt), frame, flags).is_ok() {
+                        addr += Size2MiB::SIZE;
+                        continue;
+                    }
+                    // 中间页表的Frame不足，改为4KiB的page
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+            }
+            let frame: Option<PhysFrame<Size4KiB>> = GlobalFrameAllocator.allocate_frame();
+            let mapped = frame.map_or(false, |frame| {
+                pg.try_map(Page::containing_address(virt), frame, flags)
+                    .map_err(|_| unsafe { GlobalFrameAllocator.deallocate_frame(frame) })
+                    .is_ok()
+            });
+            if !mapped {
+                // 取消已经映射的page：[top, addr)
+                let mapped_end = addr;
+                let mut addr = top;
+                while addr < mapped_end {
+                    // 持有堆的锁且已关中断，不能等待TLB shootdown；这些page还没有被使用过
+                    let size = match pg.unmap_local(VirtAddr::new(addr)) {
+                        Some((phys, size)) => {
+                            unsafe {
+                                match size {
+                                    Size4KiB::SIZE => GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)),
+                                    _ => GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(phys)),
+                                }
+                            }
+                            size
+                        },
+                        None => Size4KiB::SIZE,
+                    };
+                    addr += size;
+                }
+                return false;
+            }
+            addr += Size4KiB::SIZE;
+        }
+
+        unsafe {
+            self.fallback.extend(by);
+        }
+        true
+    }
+
+    /// slab占用的Frame的字节数
+    fn slab_bytes(&self) -> usize {
+        self.caches.iter().map(|c| c.stats().slabs * Size4KiB::SIZE as usize).sum()
+    }
+
+    /// 堆占用的总字节数（fallback的范围和slab），不能超过limit
+    fn footprint(&self) -> usize {
+        self.fallback.size() + self.slab_bytes()
+    }
+
+    /// 从SlabCache分配，新的slab不能使堆超过上限
+    fn slab_alloc(&mut self, index: usize) -> *mut u8 {
+        let grow = self.footprint() + Size4KiB::SIZE as usize <= self.limit;
+        self.caches[index].alloc(grow)
+    }
+
+    fn add_used(&mut self, size: usize) {
+        self.used += size;
+        self.high_water = self.high_water.max(self.used);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.fallback.size(),
+            slab: self.slab_bytes(),
+            used: self.used,
+            high_water: self.high_water,
+            limit: self.limit,
+        }
+    }
 }
 
 /// 为GlobalHeapAllocator实现GlobalAlloc，作为rust的堆内存分配器
+///
+/// 分配和回收时需要关中断：内核线程是可抢占的，若持有锁的线程被抢占，
+/// 中断中或其它线程再申请锁时，会一直自旋等待。
 unsafe impl GlobalAlloc for GlobalHeapLocker<GlobalHeapAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.alloc_locked(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.dealloc_locked(ptr, layout))
+    }
+}
+
+impl GlobalHeapLocker<GlobalHeapAllocator> {
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        // 将链表的第1个节点取出作为alloc的内存
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    },
-                    None => {
-                        // 最开始list_heads中全是None，未保存内存块，故需要fallback来分配内存，
-                        // 且按照BLOCK_SIZES[index]分配的相应的内存块。
-                        allocator.fallback_alloc(
-                            Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap())
-                    },
-                }
-            },
-            None => allocator.fallback_alloc(layout)
+        let (ptr, size) = match slab_index(&layout) {
+            Some(index) => (allocator.slab_alloc(index), SLAB_SIZES[index]),
+            None => (allocator.fallback_alloc(layout), layout.size()),
+        };
+        if !ptr.is_null() {
+            allocator.add_used(size);
         }
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
 
-        match list_index(&layout) {
+        match slab_index(&layout) {
             Some(index) => {
-                // 回收内存时，将适合的内存块放回list_heads中，方便下次更块分配。
-                let node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                // 回收的内存块处保存了ListNode数据，用于list_heads和ListNode.next索引
-                let node_ptr = ptr as *mut ListNode;
-                node_ptr.write(node);
-                allocator.list_heads[index] = Some(&mut *node_ptr);
+                // 回收到对象所在的slab中，slab完全空闲时归还给GlobalFrameAllocator
+                allocator.caches[index].dealloc(ptr);
+                allocator.used -= SLAB_SIZES[index];
             },
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
                 allocator.fallback.deallocate(ptr, layout);
+                allocator.used -= layout.size();
             }
         }
     }
 }
 
+/// 堆的统计信息
+pub fn stats() -> HeapStats {
+    interrupts::without_interrupts(|| HEAP_ALLOCATOR.lock().stats())
+}
 
-pub fn init() -> Result<(), MapToError<Size4KiB>> {
-    let mut pg = PageTableImpl::active();
-    let mut fa = GlobalFrameAllocator;
+/// 每种大小的SlabCache的统计信息（顺序同SLAB_SIZES）
+pub fn slab_stats() -> [SlabStats; SLAB_SIZES.len()] {
+    interrupts::without_interrupts(|| {
+        let allocator = HEAP_ALLOCATOR.lock();
+        let mut stats = [SlabStats::default(); SLAB_SIZES.len()];
+        for (s, cache) in stats.iter_mut().zip(allocator.caches.iter()) {
+            *s = cache.stats();
+        }
+        stats
+    })
+}
 
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
-        let heap_end_page = Page::<Size4KiB>::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+/// 设置堆大小的上限，上限不能小于堆的当前大小（包括slab），也不能超过HEAP_MAX_SIZE；返回实际设置的上限
+pub fn set_limit(limit: usize) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut allocator = HEAP_ALLOCATOR.lock();
+        allocator.limit = limit.max(allocator.footprint()).min(HEAP_MAX_SIZE);
+        allocator.limit
+    })
+}
 
-    for page in page_range {
-        let frame = fa
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            pg.mapper.map_to(page, frame, flags, &mut fa)?.flush()
-        }
-    }
+
+pub fn init() -> Result<(), VmaError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::register(VirtAddr::new(HEAP_START as u64), HEAP_SIZE, flags, "heap")?;
 
     unsafe {
         HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
@@ -194,3 +334,39 @@ fn test_allocator() {
     println!("current ref cnt: {}", Rc::strong_count(&cloned_ref));
     assert_eq!(Rc::strong_count(&cloned_ref), 1)
 }
+
+#[test_case]
+fn test_heap_grow() {
+    use alloc::vec::Vec;
+
+    let before = stats();
+    let mut vec: Vec<u8> = Vec::with_capacity(HEAP_SIZE * 2);
+    vec.resize(HEAP_SIZE * 2, 0x5a);
+    let after = stats();
+    println!("heap: {:?} -> {:?}", before, after);
+    assert!(after.size > HEAP_SIZE);
+    assert!(after.high_water >= before.used + HEAP_SIZE * 2);
+    assert_eq!(vec[HEAP_SIZE * 2 - 1], 0x5a);
+    drop(vec);
+    assert!(stats().used < after.used);
+
+    // 达到上限后分配失败，返回null
+    let now = stats();
+    let limit = set_limit(0);
+    assert_eq!(limit, now.size + now.slab);
+    let layout = Layout::from_size_align(HEAP_SIZE * 4, 8).unwrap();
+    assert!(unsafe { alloc::alloc::alloc(layout) }.is_null());
+    // slab用完已有的空闲对象后，同样不能再申请新的slab
+    let small = Layout::from_size_align(1024, 8).unwrap();
+    let mut objs = [null_mut(); 64];
+    let count = objs.iter_mut()
+        .map(|p| { *p = unsafe { alloc::alloc::alloc(small) }; *p })
+        .take_while(|p| !p.is_null())
+        .count();
+    assert!(count < objs.len());
+    assert_eq!(stats().slab, now.slab);
+    for &p in objs[.. count].iter() {
+        unsafe { alloc::alloc::dealloc(p, small) };
+    }
+    assert_eq!(set_limit(HEAP_MAX_SIZE), HEAP_MAX_SIZE);
+}
@@ -0,0 +1,380 @@
+//! APIC模块
+//!
+//! APIC（Advanced Programmable Interrupt Controller）包括：
+//! - Local APIC：每个CPU Core各有一个，负责接收中断、通知EOI、发送IPI和本地定时器等；
+//!   AP使用Local APIC定时器驱动线程调度（BSP使用IRQ0的Timer中断）；
+//! - IO-APIC：将外部设备的中断（GSI）重定向到指定CPU Local APIC上的指定中断号；
+//!
+//! Local APIC和IO-APIC的寄存器均通过MMIO访问，其物理地址由ACPI的MADT给出，使用ioremap以Uncached映射。
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use super::driver::acpi;
+use super::ioremap::{ioremap, CachePolicy, IoMem};
+use super::{hpet, pit};
+
+
+/// Spurious中断号，需要保证低4位为全1
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// IA32_APIC_BASE寄存器
+const IA32_APIC_BASE: u32 = 0x1b;
+/// IA32_APIC_BASE中的全局使能位
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Local APIC寄存器偏移
+mod reg {
+    pub const ID: u32 = 0x020;
+    pub const TPR: u32 = 0x080;
+    pub const EOI: u32 = 0x0b0;
+    pub const SVR: u32 = 0x0f0;
+    pub const ESR: u32 = 0x280;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const ICR_LOW: u32 = 0x300;
+    pub const ICR_HIGH: u32 = 0x310;
+    pub const TIMER_INIT: u32 = 0x380;
+    pub const TIMER_CURRENT: u32 = 0x390;
+    pub const TIMER_DIV: u32 = 0x3e0;
+}
+
+/// LVT中的屏蔽位
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT Timer：周期模式
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// 定时器分频：16
+const TIMER_DIV_16: u32 = 0x3;
+
+/// Local APIC定时器的中断号（紧接在ISA中断之后）
+pub const TIMER_VECTOR: u8 = 48;
+/// 唤醒IPI的中断号：唤醒处于hlt的CPU（如其run queue中放入了新的task），处理函数只需要EOI
+pub const WAKEUP_VECTOR: u8 = 49;
+/// cross-call IPI的中断号（见ipi模块）
+pub const CALL_VECTOR: u8 = 50;
+
+/// ICR：投递模式INIT
+const ICR_INIT: u32 = 0x5 << 8;
+/// ICR：投递模式Start-up
+const ICR_STARTUP: u32 = 0x6 << 8;
+/// ICR：Level Assert
+const ICR_ASSERT: u32 = 1 << 14;
+/// ICR：正在投递
+const ICR_PENDING: u32 = 1 << 12;
+/// ICR目标简写：所有CPU（包括自己）
+const ICR_ALL: u32 = 0b10 << 18;
+/// ICR目标简写：除自己以外的所有CPU
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+/// Local APIC的MMIO区域大小
+const LAPIC_MMIO_SIZE: usize = 0x1000;
+
+/// Local APIC
+pub struct LocalApic {
+    mmio: IoMem,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        self.mmio.read::<u32>(reg as usize)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        self.mmio.write::<u32>(reg as usize, value);
+    }
+
+    /// 当前CPU Core的APIC ID
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(reg::ID) >> 24 }
+    }
+
+    /// 通知Local APIC，已经完成中断处理
+    pub fn eoi(&self) {
+        unsafe { self.write(reg::EOI, 0) };
+    }
+
+    /// 向APIC ID为dest的CPU发送IPI，等待投递完成
+    ///
+    /// ICR需要先写高32位再写低32位，所以需要关中断，防止中断处理函数在两次写之间发送IPI。
+    unsafe fn send_ipi(&self, dest: u32, low: u32) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.write(reg::ICR_HIGH, dest << 24);
+            self.write(reg::ICR_LOW, low);
+            while self.read(reg::ICR_LOW) & ICR_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
+    /// 向APIC ID为dest的CPU发送中断号为vector的IPI（Fixed投递模式）
+    pub fn send_fixed(&self, dest: u32, vector: u8) {
+        unsafe { self.send_ipi(dest, ICR_ASSERT | vector as u32) };
+    }
+
+    /// 向所有CPU（include_self为false时不包括自己）广播中断号为vector的IPI
+    pub fn send_broadcast(&self, vector: u8, include_self: bool) {
+        let shorthand = if include_self { ICR_ALL } else { ICR_ALL_BUT_SELF };
+        unsafe { self.send_ipi(0, shorthand | ICR_ASSERT | vector as u32) };
+    }
+
+    /// 发送INIT IPI，使目标CPU进入等待Start-up IPI的状态
+    pub fn send_init(&self, dest: u32) {
+        unsafe { self.send_ipi(dest, ICR_INIT | ICR_ASSERT) };
+    }
+
+    /// 发送Start-up IPI，目标CPU从物理地址page * 0x1000处以实模式开始执行
+    pub fn send_startup(&self, dest: u32, page: u8) {
+        unsafe { self.send_ipi(dest, ICR_STARTUP | ICR_ASSERT | page as u32) };
+    }
+
+    /// 以周期模式启动定时器，每count个计数（分频16之后）触发一次TIMER_VECTOR中断
+    unsafe fn start_timer(&self, count: u32) {
+        self.write(reg::TIMER_DIV, TIMER_DIV_16);
+        self.write(reg::LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        self.write(reg::TIMER_INIT, count);
+    }
+
+    /// 使能Local APIC
+    ///
+    /// 屏蔽LVT中的定时器、LINT0/1和错误中断，这些中断目前均不需要；
+    /// 然后设置Spurious中断号并使能APIC（SVR的bit8）。
+    unsafe fn enable(&self) {
+        self.write(reg::LVT_TIMER, LVT_MASKED);
+        self.write(reg::LVT_LINT0, LVT_MASKED);
+        self.write(reg::LVT_LINT1, LVT_MASKED);
+        self.write(reg::LVT_ERROR, LVT_MASKED);
+        // 写两次ESR，清除之前的错误状态
+        self.write(reg::ESR, 0);
+        self.write(reg::ESR, 0);
+        self.write(reg::TPR, 0); // 接收所有优先级的中断
+        self.write(reg::SVR, 0x100 | SPURIOUS_VECTOR as u32);
+        self.eoi();
+    }
+}
+
+/// IO-APIC寄存器
+const IOAPIC_REG_VER: u32 = 0x01;
+const IOAPIC_REG_REDTBL: u32 = 0x10;
+/// IO-APIC的MMIO区域大小
+const IOAPIC_MMIO_SIZE: usize = 0x20;
+
+/// IO-APIC
+///
+/// IO-APIC只有IOREGSEL（偏移0x00）和IOWIN（偏移0x10）两个寄存器：
+/// 先向IOREGSEL写入寄存器索引，再通过IOWIN读写对应寄存器。
+pub struct IoApic {
+    mmio: IoMem,
+    /// 第0个输入引脚对应的GSI
+    gsi_base: u32,
+    /// 输入引脚的数量
+    count: u32,
+}
+
+impl IoApic {
+    unsafe fn new(phys: PhysAddr, gsi_base: u32) -> Self {
+        let mut ioapic = IoApic {
+            mmio: ioremap(phys, IOAPIC_MMIO_SIZE, CachePolicy::Uncached).expect("failed to map IO-APIC"),
+            gsi_base,
+            count: 0,
+        };
+        // VER寄存器的bit16~23保存了最大的重定向Entry索引
+        ioapic.count = ((ioapic.read(IOAPIC_REG_VER) >> 16) & 0xff) + 1;
+        ioapic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        self.mmio.write::<u32>(0x00, reg);
+        self.mmio.read::<u32>(0x10)
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        self.mmio.write::<u32>(0x00, reg);
+        self.mmio.write::<u32>(0x10, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.count
+    }
+
+    /// 设置重定向Entry
+    ///
+    /// 每个Entry为64位，占用2个寄存器：
+    /// - bit0~7: 中断号；bit8~10: 投递模式（0为Fixed）；bit11: 目标模式（0为Physical）；
+    /// - bit13: 极性（1为低电平有效）；bit15: 触发方式（1为电平触发）；bit16: 屏蔽位；
+    /// - bit56~63: 目标CPU的APIC ID；
+    unsafe fn set_entry(&mut self, gsi: u32, entry: u64) {
+        let index = gsi - self.gsi_base;
+        self.write(IOAPIC_REG_REDTBL + index * 2, entry as u32 | LVT_MASKED);
+        self.write(IOAPIC_REG_REDTBL + index * 2 + 1, (entry >> 32) as u32);
+        self.write(IOAPIC_REG_REDTBL + index * 2, entry as u32);
+    }
+
+    /// 屏蔽所有输入引脚
+    unsafe fn mask_all(&mut self) {
+        for index in 0 .. self.count {
+            self.set_entry(self.gsi_base + index, LVT_MASKED as u64);
+        }
+    }
+}
+
+static LAPIC: Once<LocalApic> = Once::new();
+static IOAPICS: Once<Mutex<Vec<IoApic>>> = Once::new();
+
+/// 检测CPU是否支持APIC（CPUID.01H:EDX的bit9）
+pub fn is_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+/// 初始化Local APIC和IO-APIC
+///
+/// 需要CPU支持APIC，且MADT中至少描述了一个IO-APIC，否则返回false；
+/// 初始化后IO-APIC的所有输入引脚均被屏蔽，需要使用route_isa_irq设置重定向。
+pub fn init() -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) if is_supported() && !madt.ioapics.is_empty() => madt,
+        _ => return false,
+    };
+
+    let lapic = unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let base = msr.read();
+        msr.write(base | APIC_BASE_ENABLE);
+        LocalApic {
+            mmio: ioremap(PhysAddr::new(madt.lapic_addr), LAPIC_MMIO_SIZE, CachePolicy::Uncached)
+                .expect("failed to map Local APIC"),
+        }
+    };
+    unsafe { lapic.enable() };
+    LAPIC.call_once(|| lapic);
+
+    IOAPICS.call_once(|| {
+        let ioapics = madt.ioapics
+            .iter()
+            .map(|io| unsafe {
+                let mut ioapic = IoApic::new(PhysAddr::new(io.addr as u64), io.gsi_base);
+                ioapic.mask_all();
+                ioapic
+            })
+            .collect();
+        Mutex::new(ioapics)
+    });
+
+    true
+}
+
+/// 初始化AP的Local APIC，并启动定时器（在AP上调用）
+///
+/// 所有CPU的Local APIC都映射在相同的物理地址，各自访问到的是自己的Local APIC，所以共用同一个映射。
+pub fn init_ap() {
+    let lapic = local_apic();
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let base = msr.read();
+        msr.write(base | APIC_BASE_ENABLE);
+        lapic.enable();
+    }
+    let freq = TIMER_FREQUENCY.load(Ordering::Relaxed);
+    if freq != 0 {
+        unsafe { lapic.start_timer((freq / crate::time::HZ) as u32) };
+    }
+}
+
+/// Local APIC定时器的频率（分频16之后，Hz），所有CPU相同
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// 使用HPET或PIT校准Local APIC定时器的频率（在BSP上调用，需要关中断）
+pub fn calibrate_timer() -> u64 {
+    let lapic = local_apic();
+    let freq = unsafe {
+        // 以单次模式从最大值开始递减，屏蔽中断
+        lapic.write(reg::TIMER_DIV, TIMER_DIV_16);
+        lapic.write(reg::LVT_TIMER, LVT_MASKED);
+        lapic.write(reg::TIMER_INIT, u32::MAX);
+        let read = || (u32::MAX - lapic.read(reg::TIMER_CURRENT)) as u64;
+        let freq = hpet::calibrate(10, read).unwrap_or_else(|| pit::calibrate(10, read));
+        lapic.write(reg::TIMER_INIT, 0);
+        freq
+    };
+    TIMER_FREQUENCY.store(freq, Ordering::Relaxed);
+    freq
+}
+
+/// Local APIC定时器中断（由trap_dispatch调用，已关中断）
+///
+/// 全局的tick由BSP的Timer中断（IRQ0）维护，这里只检查当前CPU上线程的时间片。
+pub(super) fn timer_interrupt() {
+    crate::kthread::tick_local();
+    eoi();
+    crate::kthread::preempt();
+}
+
+/// 唤醒IPI（由trap_dispatch调用）
+///
+/// 唤醒hlt的CPU是IPI本身的作用，这里只需要EOI。
+pub(super) fn wakeup_interrupt() {
+    eoi();
+}
+
+/// 获取当前CPU Core的Local APIC
+pub fn local_apic() -> &'static LocalApic {
+    LAPIC.get().expect("Local APIC not initialized")
+}
+
+/// 通知Local APIC，已经完成中断处理
+pub fn eoi() {
+    local_apic().eoi();
+}
+
+/// 将ISA中断（IRQ0~15）重定向到当前CPU Core的指定中断号
+///
+/// ISA中断默认为边沿触发、高电平有效，且IRQ号等于GSI；
+/// 若MADT中有对应的中断源重定向，则以重定向中的GSI、极性和触发方式为准。
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let mut gsi = irq as u32;
+    let mut entry = vector as u64;
+    if let Some(ovr) = acpi::madt().and_then(|m| m.overrides.iter().find(|o| o.source == irq)) {
+        gsi = ovr.gsi;
+        if ovr.active_low() {
+            entry |= 1 << 13;
+        }
+        if ovr.level_triggered() {
+            entry |= 1 << 15;
+        }
+    }
+    entry |= (local_apic().id() as u64) << 56;
+    set_gsi_entry(gsi, entry);
+}
+
+/// ISA中断（IRQ0~15）对应的GSI
+pub fn isa_irq_gsi(irq: u8) -> u32 {
+    acpi::madt()
+        .and_then(|m| m.overrides.iter().find(|o| o.source == irq))
+        .map_or(irq as u32, |ovr| ovr.gsi)
+}
+
+/// 屏蔽ISA中断（IRQ0~15）
+pub fn mask_isa_irq(irq: u8) {
+    set_gsi_entry(isa_irq_gsi(irq), LVT_MASKED as u64);
+}
+
+fn set_gsi_entry(gsi: u32, entry: u64) {
+    let mut ioapics = IOAPICS.get().expect("IO-APIC not initialized").lock();
+    match ioapics.iter_mut().find(|io| io.handles(gsi)) {
+        Some(ioapic) => unsafe { ioapic.set_entry(gsi, entry) },
+        None => println!("WARNING: no IO-APIC handles GSI {}", gsi),
+    }
+}
+
+
+/// Spurious中断(No = 0xFF)
+///
+/// Spurious中断不需要通知EOI。
+pub extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+}
@@ -0,0 +1,155 @@
+//! 调用栈回溯模块
+//!
+//! kernel编译时保留帧指针（.cargo/config.toml中的force-frame-pointers），每个函数的栈帧为：
+//! `[rbp + 8]`: 返回地址；`[rbp]`: 调用者的rbp；
+//! 所以从当前的rbp开始，沿着rbp链即可得到每一层的返回地址；新线程的初始rbp为0，作为链的结束。
+//!
+//! 符号表保存在kernel的.ksyms段中，该段在这里预留（KSYMS_SIZE字节），
+//! 链接后由scripts/ksyms.py写入（cargo的runner会自动执行），格式见ksyms.py。
+
+use core::arch::global_asm;
+use core::{mem, slice, str};
+use x86_64::VirtAddr;
+use super::memory::PageTableImpl;
+
+
+/// .ksyms段的大小，需要与下面global_asm中的.zero一致
+pub const KSYMS_SIZE: usize = 0x8_0000;
+
+global_asm!(r#"
+.section .ksyms, "a"
+.balign 8
+.global __ksyms
+__ksyms:
+    .zero 0x80000
+.text
+"#);
+
+extern "C" {
+    static __ksyms: [u8; KSYMS_SIZE];
+}
+
+/// 最大回溯深度
+const MAX_DEPTH: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 4],
+    count: u32,
+    strtab_size: u64,
+}
+
+#[repr(C)]
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name_off: u32,
+    name_len: u32,
+}
+
+/// 符号表（按地址排序）和字符串表；未写入符号表时返回None
+fn symbol_table() -> Option<(&'static [Symbol], &'static [u8])> {
+    unsafe {
+        let base = __ksyms.as_ptr();
+        let header = &*(base as *const Header);
+        let syms_size = header.count as usize * mem::size_of::<Symbol>();
+        if &header.magic != b"KSYM" || mem::size_of::<Header>() + syms_size + header.strtab_size as usize > KSYMS_SIZE {
+            return None;
+        }
+        let syms = slice::from_raw_parts(base.add(mem::size_of::<Header>()) as *const Symbol, header.count as usize);
+        let strtab = slice::from_raw_parts(base.add(mem::size_of::<Header>() + syms_size), header.strtab_size as usize);
+        Some((syms, strtab))
+    }
+}
+
+/// 将地址解析为函数名和函数内的偏移
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let (syms, strtab) = symbol_table()?;
+    // 最后一个起始地址不大于addr的符号
+    let index = syms.partition_point(|s| s.addr <= addr).checked_sub(1)?;
+    let sym = &syms[index];
+    if sym.size != 0 && addr >= sym.addr + sym.size {
+        return None;
+    }
+    let name = strtab.get(sym.name_off as usize .. (sym.name_off + sym.name_len) as usize)?;
+    Some((str::from_utf8(name).unwrap_or("?"), addr - sym.addr))
+}
+
+/// 从rbp开始沿rbp链回溯，对每一层的返回地址调用f
+///
+/// rbp需要按8字节对齐且已经映射，否则停止回溯。
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    let pg = PageTableImpl::active();
+    for _ in 0 .. MAX_DEPTH {
+        if rbp == 0 || rbp % 8 != 0 || VirtAddr::try_new(rbp + 8).is_err()
+            || pg.translate(VirtAddr::new(rbp)).is_none() || pg.translate(VirtAddr::new(rbp + 8)).is_none() {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+        rbp = next;
+    }
+}
+
+/// 打印一层调用栈
+fn print_frame(depth: usize, addr: u64) {
+    // 返回地址为call的下一条指令，使用addr - 1查找，避免call位于函数末尾时解析到下一个函数
+    match symbolize(addr - 1) {
+        Some((name, offset)) => println!("#{} {}+{:#x} ({:#x})", depth, name, offset + 1, addr),
+        None => println!("#{} {:#x}", depth, addr),
+    }
+}
+
+/// 打印从rip和rbp开始的调用栈（用于Exception，rip为触发Exception的指令）
+pub fn print_from(rip: u64, rbp: u64) {
+    println!("Backtrace:");
+    match symbolize(rip) {
+        Some((name, offset)) => println!("#0 {}+{:#x} ({:#x})", name, offset, rip),
+        None => println!("#0 {:#x}", rip),
+    }
+    let mut depth = 1;
+    walk(rbp, |addr| {
+        print_frame(depth, addr);
+        depth += 1;
+    });
+}
+
+/// 打印当前的调用栈
+#[inline(never)]
+pub fn print() {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    println!("Backtrace:");
+    let mut depth = 0;
+    walk(rbp, |addr| {
+        print_frame(depth, addr);
+        depth += 1;
+    });
+}
+
+
+
+/// 符号表由runner写入，可以解析kernel中的函数
+#[test_case]
+fn test_backtrace() {
+    #[inline(never)]
+    fn frames() -> alloc::vec::Vec<u64> {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        let mut addrs = alloc::vec::Vec::new();
+        walk(rbp, |addr| addrs.push(addr));
+        addrs
+    }
+
+    let (name, offset) = symbolize(frames as fn() -> alloc::vec::Vec<u64> as u64 + 1).expect("no kernel symbols");
+    assert!(name.ends_with("test_backtrace::frames"), "{}", name);
+    assert_eq!(offset, 1);
+
+    // frames的返回地址位于test_backtrace中
+    let addrs = frames();
+    assert!(!addrs.is_empty());
+    assert!(symbolize(addrs[0] - 1).unwrap().0.ends_with("test_backtrace"));
+}
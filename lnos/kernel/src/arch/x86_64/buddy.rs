@@ -0,0 +1,174 @@
+//! Buddy物理Frame分配器
+//!
+//! 以2的幂个连续Frame（order）为单位分配物理内存，最大为2^MAX_ORDER个Frame：
+//! - 每个order有一个空闲块链表，链表节点直接保存在空闲块的第一个Frame中（通过phys_to_virt访问）；
+//! - meta为每个Frame保存一个字节，空闲块的第一个Frame记录FREE和order，用于回收时判断buddy是否空闲；
+//! - meta的大小由BootInfo的memory_map决定，从可用内存中划出，所以可以管理任意大小的物理内存。
+
+use core::ptr::null_mut;
+use x86_64::PhysAddr;
+use super::memory::phys_to_virt;
+
+
+/// 最大的order（2^10个Frame，即4MiB）
+pub const MAX_ORDER: usize = 10;
+
+/// meta中表示空闲块的标记
+const FREE: u8 = 0x80;
+
+const FRAME_SIZE: usize = 4096;
+
+/// 空闲块链表的节点
+struct FreeBlock {
+    prev: *mut FreeBlock,
+    next: *mut FreeBlock,
+}
+
+/// Buddy分配器，地址均以Frame号表示
+pub struct BuddyAllocator {
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1],
+    meta: *mut u8,
+    /// meta覆盖的Frame数
+    frames: usize,
+    /// 可用的Frame总数
+    total: usize,
+    /// 空闲的Frame数
+    free: usize,
+}
+
+/// BuddyAllocator只在FRAME_ALLOCATOR的锁中访问
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: [null_mut(); MAX_ORDER + 1],
+            meta: null_mut(),
+            frames: 0,
+            total: 0,
+            free: 0,
+        }
+    }
+
+    /// meta需要的字节数
+    pub fn meta_size(frames: usize) -> usize {
+        frames
+    }
+
+    /// 设置meta，meta位于物理地址meta_phys处，覆盖[0, frames)的Frame
+    pub unsafe fn init(&mut self, meta_phys: PhysAddr, frames: usize) {
+        self.meta = phys_to_virt(meta_phys).as_mut_ptr();
+        self.frames = frames;
+        core::ptr::write_bytes(self.meta, 0, Self::meta_size(frames));
+    }
+
+    /// 添加可用的Frame：[start, end)
+    pub fn add_range(&mut self, start: usize, end: usize) {
+        let end = end.min(self.frames);
+        let mut frame = start;
+        while frame < end {
+            // 按frame的对齐和剩余的长度，选择最大的order
+            let mut order = MAX_ORDER;
+            while frame & ((1 << order) - 1) != 0 || frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.total += 1 << order;
+            unsafe { self.dealloc(frame, order) };
+            frame += 1 << order;
+        }
+    }
+
+    /// 分配2^order个连续的Frame，返回第一个Frame号
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut k = (order ..= MAX_ORDER).find(|&k| !self.free_lists[k].is_null())?;
+        let frame = unsafe { self.pop(k) };
+        // 将多余的部分拆分成buddy放回链表
+        while k > order {
+            k -= 1;
+            unsafe { self.push(frame + (1 << k), k) };
+        }
+        unsafe { *self.meta.add(frame) = order as u8 };
+        self.free -= 1 << order;
+        Some(frame)
+    }
+
+    /// 回收由alloc(order)分配的Frame，并与空闲的buddy合并
+    pub unsafe fn dealloc(&mut self, frame: usize, order: usize) {
+        self.free += 1 << order;
+        let (mut frame, mut order) = (frame, order);
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy + (1 << order) > self.frames || *self.meta.add(buddy) != FREE | order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// 可用的Frame总数
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// 空闲的Frame数
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    /// 每个order的空闲块数
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut blocks = [0; MAX_ORDER + 1];
+        for (k, count) in blocks.iter_mut().enumerate() {
+            let mut node = self.free_lists[k];
+            while !node.is_null() {
+                *count += 1;
+                node = unsafe { (*node).next };
+            }
+        }
+        blocks
+    }
+
+    fn block(frame: usize) -> *mut FreeBlock {
+        phys_to_virt(PhysAddr::new((frame * FRAME_SIZE) as u64)).as_mut_ptr()
+    }
+
+    fn frame_of(block: *mut FreeBlock) -> usize {
+        super::memory::virt_to_phys(x86_64::VirtAddr::from_ptr(block)).as_u64() as usize / FRAME_SIZE
+    }
+
+    unsafe fn push(&mut self, frame: usize, order: usize) {
+        let block = Self::block(frame);
+        (*block).prev = null_mut();
+        (*block).next = self.free_lists[order];
+        if !self.free_lists[order].is_null() {
+            (*self.free_lists[order]).prev = block;
+        }
+        self.free_lists[order] = block;
+        *self.meta.add(frame) = FREE | order as u8;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> usize {
+        let frame = Self::frame_of(self.free_lists[order]);
+        self.remove(frame, order);
+        frame
+    }
+
+    unsafe fn remove(&mut self, frame: usize, order: usize) {
+        let block = Self::block(frame);
+        if (*block).prev.is_null() {
+            self.free_lists[order] = (*block).next;
+        } else {
+            (*(*block).prev).next = (*block).next;
+        }
+        if !(*block).next.is_null() {
+            (*(*block).next).prev = (*block).prev;
+        }
+        *self.meta.add(frame) = 0;
+    }
+}
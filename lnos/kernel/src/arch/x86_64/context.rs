@@ -0,0 +1,138 @@
+//! 线程上下文模块
+//!
+//! 线程切换是通过普通的函数调用（switch）完成的，根据System V ABI，
+//! 调用方已经保存了caller-saved寄存器，所以switch只需要保存callee-saved寄存器：
+//! rbx、rbp、r12~r15，以及栈指针rsp（保存在线程的Context中）。
+//!
+//! 切换时的栈布局（从高地址到低地址）：
+//! `返回地址 | rbp | rbx | r12 | r13 | r14 | r15` <- rsp
+//!
+//! catch/throw使用相同的布局：catch保存callee-saved寄存器和栈指针后调用函数，
+//! throw恢复栈指针，从catch中返回，跳过之间的所有栈帧（不会执行drop）。
+
+use core::arch::global_asm;
+
+
+global_asm!(r#"
+.global __context_switch
+__context_switch:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global __context_trampoline
+__context_trampoline:
+    mov rdi, r12
+    call r13
+    ud2
+
+.global __context_catch
+__context_catch:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    sub rsp, 8
+    mov rdi, rdx
+    call rsi
+    add rsp, 8
+    xor eax, eax
+    jmp 1f
+
+.global __context_throw
+__context_throw:
+    mov rsp, [rdi]
+    mov eax, 1
+1:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#);
+
+extern "C" {
+    fn __context_switch(from: *mut usize, to: usize);
+    fn __context_trampoline();
+    fn __context_catch(point: *mut usize, f: extern "C" fn(usize), arg: usize) -> usize;
+    fn __context_throw(point: *const usize) -> !;
+}
+
+/// 线程上下文，即线程切换出去时的栈指针
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    rsp: usize,
+}
+
+impl Context {
+    /// 在新的栈上构造初始上下文
+    ///
+    /// 第一次切换到该上下文时，会通过__context_trampoline调用entry(arg)，且entry不能返回。
+    /// r12和r13分别用于传递arg和entry。
+    pub fn new(stack_top: usize, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        // 返回地址放在stack_top - 8处，使得调用entry时，满足rsp + 8按16字节对齐
+        let stack_top = stack_top & !0xf;
+        let trampoline = __context_trampoline as unsafe extern "C" fn() as usize;
+        let frame: [usize; 7] = [
+            0,                                      // r15
+            0,                                      // r14
+            entry as usize,                         // r13
+            arg,                                    // r12
+            0,                                      // rbx
+            0,                                      // rbp
+            trampoline,                             // 返回地址
+        ];
+        let rsp = stack_top - 8 - 6 * 8;
+        unsafe {
+            (rsp as *mut [usize; 7]).write(frame);
+        }
+        Context { rsp }
+    }
+
+    /// 保存当前上下文到from，并切换到to
+    ///
+    /// 调用时需要关中断，且from和to在切换期间必须有效。
+    pub unsafe fn switch(from: *mut Context, to: *const Context) {
+        __context_switch(&mut (*from).rsp as *mut usize, (*to).rsp);
+    }
+}
+
+/// 捕获点，即调用catch时的栈指针
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct CatchPoint {
+    rsp: usize,
+}
+
+impl CatchPoint {
+    /// 调用f(arg)，f正常返回时返回false；
+    /// f（或其调用的函数）中对该捕获点调用throw时，直接从catch返回true
+    ///
+    /// 调用者需要保证throw时仍在同一个栈上、catch还没有返回，且self在此期间有效。
+    pub unsafe fn catch(&mut self, f: extern "C" fn(usize), arg: usize) -> bool {
+        __context_catch(&mut self.rsp as *mut usize, f, arg) != 0
+    }
+
+    /// 返回到catch，catch与throw之间的栈帧被丢弃（其中的值不会drop，持有的锁也不会释放）
+    pub unsafe fn throw(point: *const CatchPoint) -> ! {
+        __context_throw(&(*point).rsp as *const usize)
+    }
+}
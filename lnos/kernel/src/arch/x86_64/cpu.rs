@@ -0,0 +1,182 @@
+//! Per-CPU模块
+//!
+//! 每个CPU Core有一个Cpu结构体（GDT、TSS、IST栈、CPU编号、当前线程等），
+//! 通过GS段访问：内核态时IA32_GS_BASE指向当前CPU的Cpu，Cpu的第一个字段保存自身的地址，
+//! 所以`mov rax, gs:[0]`即可得到当前CPU的Cpu。
+//!
+//! 用户态时GS由用户使用：从用户态进入内核时（syscall、中断）使用swapgs交换IA32_GS_BASE和IA32_KERNEL_GS_BASE，
+//! 返回用户态前再交换回来；内核态之间的中断不需要交换。
+//!
+//! CPU编号（id）按启动顺序分配，BSP为0；APIC ID由硬件决定，不一定连续。
+
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::ptr;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use super::gdt::{CpuTables, IST_COUNT};
+
+
+/// 最多支持的CPU数量
+pub const MAX_CPUS: usize = 16;
+
+/// 一个CPU Core的数据
+///
+/// syscall入口通过GS直接访问前面的几个字段，所以字段的顺序不能修改（见syscall模块）。
+#[repr(C)]
+pub struct Cpu {
+    /// 自身的地址（gs:[0]）
+    self_ptr: *const Cpu,
+    /// syscall入口使用的内核栈（gs:[8]）
+    pub(super) syscall_kernel_rsp: u64,
+    /// syscall入口暂存的用户栈（gs:[16]）
+    pub(super) syscall_user_rsp: u64,
+    id: usize,
+    apic_id: u32,
+    /// 当前线程的id
+    current_thread: AtomicU64,
+    /// GDT、TSS和IST栈
+    pub(super) tables: CpuTables,
+}
+
+const _: () = assert!(core::mem::offset_of!(Cpu, syscall_kernel_rsp) == 8);
+const _: () = assert!(core::mem::offset_of!(Cpu, syscall_user_rsp) == 16);
+
+impl Cpu {
+    pub const fn new(id: usize, apic_id: u32) -> Self {
+        Cpu {
+            self_ptr: ptr::null(),
+            syscall_kernel_rsp: 0,
+            syscall_user_rsp: 0,
+            id,
+            apic_id,
+            current_thread: AtomicU64::new(0),
+            tables: CpuTables::new(),
+        }
+    }
+
+    /// CPU编号（BSP为0）
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// 当前在该CPU上执行的线程的id
+    pub fn current_thread(&self) -> u64 {
+        self.current_thread.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_thread(&self, id: u64) {
+        self.current_thread.store(id, Ordering::Relaxed);
+    }
+}
+
+/// BSP的Cpu：BSP初始化GDT时还没有堆，所以使用静态变量
+static mut BSP_CPU: Cpu = Cpu::new(0, 0);
+
+/// 所有已经启动的CPU，下标为CPU编号
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<Cpu> = AtomicPtr::new(ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
+
+/// 已经启动的CPU数量
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// 当前CPU的初始APIC ID（CPUID.01H:EBX的bit24~31），不需要先初始化Local APIC
+pub fn initial_apic_id() -> u32 {
+    unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24
+}
+
+/// 初始化当前CPU：加载GDT和TSS，设置GS，并登记为已启动
+///
+/// ist_names为IST栈（Double Fault、NMI、Machine Check）的名称（用于栈溢出报告）。
+unsafe fn setup(cpu: &'static mut Cpu, ist_names: [&str; IST_COUNT]) {
+    let ptr = cpu as *mut Cpu;
+    cpu.self_ptr = ptr;
+    (*ptr).tables.load(ist_names);
+    GsBase::write(VirtAddr::new(ptr as u64));
+    KernelGsBase::write(VirtAddr::new(0));
+    CPUS[(*ptr).id].store(ptr, Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+}
+
+/// 初始化BSP
+///
+/// 在memory::init之后调用，之后才能使用this_cpu。
+pub fn init() {
+    unsafe {
+        BSP_CPU.apic_id = initial_apic_id();
+        setup(&mut *ptr::addr_of_mut!(BSP_CPU), ["double fault", "nmi", "machine check"]);
+    }
+}
+
+/// 初始化AP（在AP上调用），cpu由smp模块分配
+pub(super) unsafe fn init_ap(cpu: &'static mut Cpu) {
+    let names = [
+        alloc::format!("double fault/{}", cpu.id),
+        alloc::format!("nmi/{}", cpu.id),
+        alloc::format!("machine check/{}", cpu.id),
+    ];
+    setup(cpu, [&names[0], &names[1], &names[2]]);
+}
+
+/// 当前CPU的Cpu
+pub fn this_cpu() -> &'static Cpu {
+    unsafe { &*this_cpu_ptr() }
+}
+
+/// 当前CPU的Cpu（可修改）
+///
+/// 调用者需要关中断，防止修改期间被切换到其它CPU。
+pub(super) unsafe fn this_cpu_mut() -> &'static mut Cpu {
+    &mut *(this_cpu_ptr() as *mut Cpu)
+}
+
+fn this_cpu_ptr() -> *const Cpu {
+    let ptr: *const Cpu;
+    unsafe { core::arch::asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags)) };
+    debug_assert!(!ptr.is_null(), "per-CPU data not initialized");
+    ptr
+}
+
+/// 当前CPU的编号
+pub fn id() -> usize {
+    this_cpu().id
+}
+
+/// 已经启动的CPU数量
+pub fn count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// 获取编号为id的CPU（未启动时返回None）
+pub fn get(id: usize) -> Option<&'static Cpu> {
+    let ptr = CPUS.get(id)?.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// 对每个已经启动的CPU调用f
+pub fn for_each_cpu(mut f: impl FnMut(&'static Cpu)) {
+    CPUS.iter()
+        .filter_map(|cpu| unsafe { cpu.load(Ordering::Acquire).as_ref() })
+        .for_each(|cpu| f(cpu));
+}
+
+
+
+/// BSP的编号为0，且可以通过GS访问
+#[test_case]
+fn test_this_cpu() {
+    let cpu = this_cpu();
+    assert!(core::ptr::eq(get(cpu.id()).unwrap(), cpu));
+    assert!(count() >= 1);
+    let mut ids = alloc::vec::Vec::new();
+    for_each_cpu(|c| ids.push(c.id()));
+    assert_eq!(ids.len(), count());
+    assert_eq!(ids[0], 0);
+    assert!(ids.contains(&id()));
+}
@@ -1,8 +1,20 @@
+//! ACPI模块
+//!
 //! 将退出qemu当成简单的电源关机处理
 //!
 //! 对于qemu：
 //! - 通过操作isa-debug-exit设备来实现qemu的退出；
 //! - 通过serial设备，访问qemu的屏幕（stdio）内容，并输出到Host主机终端；
+//!
+//! 解析ACPI表：
+//! - 在EBDA和BIOS只读区域中查找RSDP，再通过RSDT/XSDT找到其它的SDT；
+//! - 解析MADT（签名为"APIC"），获取Local APIC、IO-APIC和ISA中断重定向等信息；
+
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::arch::memory::phys_to_virt;
 
 
 /// qemu退出码
@@ -21,3 +33,257 @@ pub fn exit_qemu(ecode: QemuExitCode) {
         port.write(ecode as u32);
     }
 }
+
+
+/// RSDP（Root System Description Pointer）
+///
+/// ACPI 1.0只有前20字节，ACPI 2.0及以后扩展了xsdt_addr等字段。
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_addr: u32,
+    length: u32,
+    xsdt_addr: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// 所有SDT（System Description Table）共有的表头
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// MADT中的Local APIC
+#[derive(Debug, Clone, Copy)]
+pub struct MadtLocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// bit0: 已使能；bit1: 可上线（Online Capable）
+    pub flags: u32,
+}
+
+impl MadtLocalApic {
+    pub fn usable(&self) -> bool {
+        self.flags & 0b11 != 0
+    }
+}
+
+/// MADT中的IO-APIC
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub addr: u32,
+    /// IO-APIC第0个输入引脚对应的GSI（Global System Interrupt）
+    pub gsi_base: u32,
+}
+
+/// MADT中的中断源重定向（Interrupt Source Override）
+///
+/// 用于描述ISA IRQ与GSI不是恒等映射的情况，如qemu中IRQ0（PIT）连接到GSI2。
+#[derive(Debug, Clone, Copy)]
+pub struct MadtOverride {
+    pub source: u8,
+    pub gsi: u32,
+    /// bit0~1: 极性（0b11为低电平有效）；bit2~3: 触发方式（0b11为电平触发）
+    pub flags: u16,
+}
+
+impl MadtOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// MADT（Multiple APIC Description Table）
+#[derive(Debug)]
+pub struct Madt {
+    /// Local APIC的物理地址
+    pub lapic_addr: u64,
+    /// 系统是否同时存在8259 PIC
+    pub pcat_compat: bool,
+    pub lapics: Vec<MadtLocalApic>,
+    pub ioapics: Vec<MadtIoApic>,
+    pub overrides: Vec<MadtOverride>,
+}
+
+/// ACPI表信息
+pub struct Acpi {
+    /// RSDT/XSDT中所有SDT的物理地址
+    sdts: Vec<PhysAddr>,
+    madt: Option<Madt>,
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// 解析ACPI表
+///
+/// 需要在memory::init和allocator::init之后调用；
+/// 未找到RSDP时（如非ACPI的平台），后续acpi::find_table等均返回None。
+pub fn init() {
+    ACPI.call_once(|| {
+        let sdts = match find_rsdp() {
+            Some(rsdp) => unsafe { parse_root_sdt(&rsdp) },
+            None => {
+                println!("ACPI: RSDP not found");
+                Vec::new()
+            },
+        };
+        let madt = sdts
+            .iter()
+            .find(|&&addr| unsafe { read_header(addr).signature == *b"APIC" })
+            .map(|&addr| unsafe { parse_madt(addr) });
+        Acpi { sdts, madt }
+    });
+}
+
+/// 根据签名查找SDT，返回其物理地址
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    ACPI.get()?
+        .sdts
+        .iter()
+        .copied()
+        .find(|&addr| unsafe { read_header(addr).signature == *signature })
+}
+
+/// 获取MADT
+pub fn madt() -> Option<&'static Madt> {
+    ACPI.get()?.madt.as_ref()
+}
+
+/// 读取SDT表头
+pub unsafe fn read_header(addr: PhysAddr) -> SdtHeader {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr::<SdtHeader>())
+}
+
+/// 校验和：所有字节相加的低8位为0
+unsafe fn checksum(addr: PhysAddr, len: usize) -> bool {
+    let bytes = slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len);
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// 查找RSDP
+///
+/// RSDP位于EBDA的前1KiB中，或者位于0xE0000~0xFFFFF的BIOS只读区域中，且按16字节对齐。
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = unsafe {
+        ptr::read_unaligned(phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>())
+    } as u64 * 16;
+    let ranges = [(ebda, ebda + 0x400), (0xe0000, 0x100000)];
+
+    for &(start, end) in ranges.iter().filter(|r| r.0 != 0) {
+        for addr in (start .. end).step_by(16) {
+            let phys = PhysAddr::new(addr);
+            let rsdp = unsafe { ptr::read_unaligned(phys_to_virt(phys).as_ptr::<Rsdp>()) };
+            if rsdp.signature == *b"RSD PTR " && unsafe { checksum(phys, 20) } {
+                return Some(rsdp);
+            }
+        }
+    }
+    None
+}
+
+/// 解析RSDT/XSDT，获取所有SDT的物理地址
+unsafe fn parse_root_sdt(rsdp: &Rsdp) -> Vec<PhysAddr> {
+    // ACPI 2.0及以后使用64位地址的XSDT，否则使用32位地址的RSDT
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_addr != 0 {
+        (PhysAddr::new(rsdp.xsdt_addr), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_addr as u64), 4)
+    };
+    let header = read_header(root);
+    let count = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let entries = phys_to_virt(root).as_ptr::<u8>().add(mem::size_of::<SdtHeader>());
+
+    (0 .. count)
+        .map(|k| {
+            let ptr = entries.add(k * entry_size);
+            if entry_size == 8 {
+                PhysAddr::new(ptr::read_unaligned(ptr as *const u64))
+            } else {
+                PhysAddr::new(ptr::read_unaligned(ptr as *const u32) as u64)
+            }
+        })
+        .filter(|&addr| checksum(addr, read_header(addr).length as usize))
+        .collect()
+}
+
+/// 解析MADT
+///
+/// MADT的表头之后为Local APIC地址（u32）和标志（u32），
+/// 然后是若干个变长的Entry，每个Entry以类型（u8）和长度（u8）开头。
+unsafe fn parse_madt(addr: PhysAddr) -> Madt {
+    let header = read_header(addr);
+    let base = phys_to_virt(addr).as_ptr::<u8>();
+    let read_u8 = |ofs: usize| *base.add(ofs);
+    let read_u16 = |ofs: usize| ptr::read_unaligned(base.add(ofs) as *const u16);
+    let read_u32 = |ofs: usize| ptr::read_unaligned(base.add(ofs) as *const u32);
+    let read_u64 = |ofs: usize| ptr::read_unaligned(base.add(ofs) as *const u64);
+
+    let hdr_len = mem::size_of::<SdtHeader>();
+    let mut madt = Madt {
+        lapic_addr: read_u32(hdr_len) as u64,
+        pcat_compat: read_u32(hdr_len + 4) & 0x1 != 0,
+        lapics: Vec::new(),
+        ioapics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut ofs = hdr_len + 8;
+    while ofs + 2 <= header.length as usize {
+        let len = read_u8(ofs + 1) as usize;
+        if len < 2 {
+            break;
+        }
+        match read_u8(ofs) {
+            0 => madt.lapics.push(MadtLocalApic {
+                processor_id: read_u8(ofs + 2),
+                apic_id: read_u8(ofs + 3),
+                flags: read_u32(ofs + 4),
+            }),
+            1 => madt.ioapics.push(MadtIoApic {
+                id: read_u8(ofs + 2),
+                addr: read_u32(ofs + 4),
+                gsi_base: read_u32(ofs + 8),
+            }),
+            2 => madt.overrides.push(MadtOverride {
+                source: read_u8(ofs + 3),
+                gsi: read_u32(ofs + 4),
+                flags: read_u16(ofs + 8),
+            }),
+            5 => madt.lapic_addr = read_u64(ofs + 4),
+            _ => {},
+        }
+        ofs += len;
+    }
+
+    madt
+}
+
+
+
+#[test_case]
+fn test_acpi_madt() {
+    if let Some(madt) = madt() {
+        println!("LAPIC at 0x{:x}, {} cpu(s), {} ioapic(s)",
+            madt.lapic_addr, madt.lapics.len(), madt.ioapics.len());
+        assert!(madt.lapics.len() >= 1);
+    }
+}
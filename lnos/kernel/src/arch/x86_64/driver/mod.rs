@@ -3,3 +3,4 @@
 pub mod vga;
 pub mod serial;
 pub mod acpi;
+pub mod rtc;
@@ -0,0 +1,220 @@
+//! RTC模块
+//!
+//! CMOS RTC（Real Time Clock）保存日期和时间，通过端口0x70选择寄存器、端口0x71读写：
+//! - 秒、分、时、日、月、年分别位于寄存器0x00、0x02、0x04、0x07、0x08、0x09，
+//!   世纪所在的寄存器由FADT的century字段指定（为0时表示没有，默认为20xx年）；
+//! - Status B决定数值格式：bit2为0时为BCD，bit1为0时为12小时制（小时的bit7表示PM）；
+//! - RTC每秒更新一次，更新期间（Status A的bit7）读取的值可能不一致，
+//!   所以需要等待更新结束，并连续读取两次相同的值。
+//!
+//! RTC还可以以32768 >> (rate - 1)的频率产生周期中断（IRQ8），需要读取Status C才会产生下一个中断。
+//!
+//! 选择寄存器和读写数据需要连续进行，多个CPU（包括IRQ8的处理函数）之间由CMOS锁保护，只在关中断时持有。
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use crate::arch::irq;
+use crate::time::{self, DateTime};
+use super::acpi;
+
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// 选择寄存器时同时屏蔽NMI（访问结束后重新选择寄存器，清除该位以恢复NMI）
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+/// Status A：正在更新
+const STATUS_A_UIP: u8 = 0x80;
+/// Status B：24小时制
+const STATUS_B_24H: u8 = 0x02;
+/// Status B：二进制格式
+const STATUS_B_BINARY: u8 = 0x04;
+/// Status B：使能周期中断
+const STATUS_B_PIE: u8 = 0x40;
+
+/// RTC的中断号
+pub const RTC_IRQ: u8 = 8;
+
+/// FADT中century字段的偏移
+const FADT_CENTURY: usize = 108;
+
+/// 周期中断的次数
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// CMOS的锁（端口0x70/0x71），只能在关中断时持有
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// 读取CMOS寄存器（需要关中断并持有CMOS锁，防止选择寄存器后被打断）
+unsafe fn read_cmos(reg: u8) -> u8 {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    index.write(NMI_DISABLE | reg);
+    let value = Port::<u8>::new(CMOS_DATA).read();
+    index.write(reg);
+    value
+}
+
+unsafe fn write_cmos(reg: u8, value: u8) {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    index.write(NMI_DISABLE | reg);
+    Port::<u8>::new(CMOS_DATA).write(value);
+    index.write(reg);
+}
+
+/// 世纪所在的寄存器
+fn century_register() -> Option<u8> {
+    let fadt = acpi::find_table(b"FACP")?;
+    let header = unsafe { acpi::read_header(fadt) };
+    if (header.length as usize) <= FADT_CENTURY {
+        return None;
+    }
+    let reg = unsafe { *crate::arch::memory::phys_to_virt(fadt + FADT_CENTURY).as_ptr::<u8>() };
+    if reg != 0 { Some(reg) } else { None }
+}
+
+/// RTC寄存器的原始值
+#[derive(PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+unsafe fn read_raw(century: Option<u8>) -> Raw {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UIP != 0 {
+        core::hint::spin_loop();
+    }
+    Raw {
+        second: read_cmos(REG_SECOND),
+        minute: read_cmos(REG_MINUTE),
+        hour: read_cmos(REG_HOUR),
+        day: read_cmos(REG_DAY),
+        month: read_cmos(REG_MONTH),
+        year: read_cmos(REG_YEAR),
+        century: century.map_or(0, |reg| read_cmos(reg)),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// 读取RTC的日期和时间（RTC保存的时间视为UTC）
+pub fn read() -> DateTime {
+    let century_reg = century_register();
+    let (raw, status_b) = interrupts::without_interrupts(|| unsafe {
+        let _cmos = CMOS.lock();
+        // 连续两次读取的值相同，才说明没有在读取期间更新
+        let mut raw = read_raw(century_reg);
+        loop {
+            let again = read_raw(century_reg);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_cmos(REG_STATUS_B))
+    });
+
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+    // 12小时制时，小时的bit7表示PM，12点表示0点（AM）或12点（PM）
+    let pm = status_b & STATUS_B_24H == 0 && raw.hour & 0x80 != 0;
+    let mut hour = convert(raw.hour & 0x7f);
+    if status_b & STATUS_B_24H == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = if century_reg.is_some() { convert(raw.century) as u16 } else { 20 };
+    DateTime {
+        year: century * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+/// 读取RTC并设置系统的启动时间
+///
+/// 需要在acpi::init之后调用。
+pub fn init() {
+    let now = read();
+    time::set_boot_time(now);
+    println!("RTC: {}", now);
+}
+
+/// RTC周期中断(IRQ8)
+fn periodic_handler(_irq: u8) {
+    // 读取Status C，否则不会产生下一个中断
+    let _cmos = CMOS.lock();
+    unsafe { read_cmos(REG_STATUS_C) };
+    PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 使能周期中断，频率为32768 >> (rate - 1)（rate为3~15，即8192Hz~2Hz），返回实际的频率
+pub fn enable_periodic(rate: u8) -> Result<u32, irq::IrqError> {
+    let rate = rate.clamp(3, 15);
+    irq::register_irq(RTC_IRQ, periodic_handler)?;
+    interrupts::without_interrupts(|| unsafe {
+        let _cmos = CMOS.lock();
+        let a = read_cmos(REG_STATUS_A);
+        write_cmos(REG_STATUS_A, (a & 0xf0) | rate);
+        let b = read_cmos(REG_STATUS_B);
+        write_cmos(REG_STATUS_B, b | STATUS_B_PIE);
+        read_cmos(REG_STATUS_C);
+    });
+    Ok(32768 >> (rate - 1))
+}
+
+/// 关闭周期中断
+pub fn disable_periodic() {
+    interrupts::without_interrupts(|| unsafe {
+        let _cmos = CMOS.lock();
+        let b = read_cmos(REG_STATUS_B);
+        write_cmos(REG_STATUS_B, b & !STATUS_B_PIE);
+    });
+    let _ = irq::unregister_irq(RTC_IRQ, periodic_handler);
+}
+
+/// 周期中断的次数
+pub fn periodic_count() -> u64 {
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+
+
+/// RTC的时间合理，周期中断可以触发
+#[test_case]
+fn test_rtc() {
+    let now = read();
+    assert!(now.year >= 2000 && (1 ..= 12).contains(&now.month) && (1 ..= 31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+    assert_eq!(from_bcd(0x59), 59);
+
+    let count = periodic_count();
+    assert_eq!(enable_periodic(6), Ok(1024));
+    interrupts::enable();
+    while periodic_count() < count + 3 {
+        x86_64::instructions::hlt();
+    }
+    interrupts::disable();
+    disable_periodic();
+}
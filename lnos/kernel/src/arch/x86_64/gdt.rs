@@ -1,55 +1,115 @@
 //! GDT模块
 //!
 //! GDT的初始化是使用lgdt指令，将GDT的地址和长度，加载GDTR寄存器。
+//! 每个CPU Core有各自的GDT和TSS（TSS中的内核栈和IST栈不能共享），由cpu模块在启动CPU时加载。
+//!
+//! GDT中Entry的顺序需要满足SYSCALL/SYSRET的要求（见syscall模块）：
+//! `null | kernel code | kernel data | user data | user code | tss`
 
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{
     GlobalDescriptorTable,
     Descriptor,
+    SegmentSelector,
 };
 use x86_64::structures::tss::TaskStateSegment;
+use super::cpu;
+use super::kstack::KernelStack;
 
 
 
 /// Double Fault的中断栈帧保存在IST[0]处
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-/// Double Fault的中断栈帧大小
-pub const DOUBLE_FAULT_IST_STACK_SIZE: usize = 0x1000 * 5;
+/// NMI的中断栈帧保存在IST[1]处
+pub const NMI_IST_INDEX: u16 = 1;
+
+/// Machine Check的中断栈帧保存在IST[2]处
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// 使用IST的Exception数量（Double Fault、NMI、Machine Check）
+pub const IST_COUNT: usize = 3;
+
+/// 每个IST栈的大小
+pub const IST_STACK_SIZE: usize = 0x1000 * 5;
+
+/// GDT中各个段的Selector
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
 
-/// 一个Cpu Core需要的相关数据
-struct Cpu {
+/// 每个CPU Core各自的GDT、TSS和IST栈（保存在Per-CPU数据中，见cpu模块）
+pub struct CpuTables {
     gdt: GlobalDescriptorTable,
     tss: TaskStateSegment,
-    double_fault_stack: [u8; DOUBLE_FAULT_IST_STACK_SIZE],
+    selectors: Option<Selectors>,
+    /// IST使用的栈（带guard page，由kstack模块分配），下标为IST下标
+    ist_stacks: [Option<KernelStack>; IST_COUNT],
 }
 
-impl Cpu {
-    unsafe fn init(&'static mut self) {
-        use x86_64::instructions::segmentation::set_cs;
+impl CpuTables {
+    pub const fn new() -> Self {
+        CpuTables {
+            gdt: GlobalDescriptorTable::new(),
+            tss: TaskStateSegment::new(),
+            selectors: None,
+            ist_stacks: [None, None, None],
+        }
+    }
+
+    /// 创建GDT并加载到当前CPU
+    ///
+    /// self需要位于Per-CPU数据中（'static），因为GDT和TSS在加载后仍由CPU访问。
+    /// ist_names为各个IST栈的名称（用于栈溢出报告），按IST下标排列。
+    pub(super) unsafe fn load(&'static mut self, ist_names: [&str; IST_COUNT]) {
+        use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
         use x86_64::instructions::tables::load_tss;
 
-        // 设置Double Fault的IST
-        let stack_top = VirtAddr::from_ptr(&self.double_fault_stack) + DOUBLE_FAULT_IST_STACK_SIZE;
-        self.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_top;
+        // 设置Double Fault、NMI和Machine Check的IST
+        for (index, name) in ist_names.iter().enumerate() {
+            let stack = KernelStack::new(IST_STACK_SIZE, name)
+                .expect("failed to allocate IST stack");
+            self.tss.interrupt_stack_table[index] = stack.top();
+            self.ist_stacks[index] = Some(stack);
+        }
 
         // 设置Selector
-        let code_selector = self.gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = self.gdt.add_entry(Descriptor::tss_segment(&self.tss));
+        let selectors = Selectors {
+            kernel_code: self.gdt.add_entry(Descriptor::kernel_code_segment()),
+            kernel_data: self.gdt.add_entry(Descriptor::kernel_data_segment()),
+            user_data: self.gdt.add_entry(Descriptor::user_data_segment()),
+            user_code: self.gdt.add_entry(Descriptor::user_code_segment()),
+            tss: self.gdt.add_entry(Descriptor::tss_segment(&self.tss)),
+        };
         self.gdt.load();
-        set_cs(code_selector);
-        load_tss(tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+        self.selectors = Some(selectors);
     }
 }
 
-static mut CPUS: Cpu = Cpu {
-    gdt: GlobalDescriptorTable::new(),
-    tss: TaskStateSegment::new(),
-    double_fault_stack: [0u8; DOUBLE_FAULT_IST_STACK_SIZE],
-};
+/// 获取GDT中各个段的Selector（所有CPU的GDT布局相同）
+pub fn selectors() -> Selectors {
+    cpu::this_cpu().tables.selectors.expect("GDT not initialized")
+}
 
-pub fn init() {
+/// 设置当前CPU从用户态进入内核态时使用的内核栈
+///
+/// 中断时CPU从TSS的privilege_stack_table[0]获取内核栈，
+/// 而SYSCALL不会切换栈，需要由syscall入口自行切换，所以两处都需要设置。
+/// 需要在关中断时调用。
+pub fn set_kernel_stack(stack_top: VirtAddr) {
     unsafe {
-        CPUS.init();
+        let cpu = cpu::this_cpu_mut();
+        cpu.tables.tss.privilege_stack_table[0] = stack_top;
+        cpu.syscall_kernel_rsp = stack_top.as_u64();
     }
 }
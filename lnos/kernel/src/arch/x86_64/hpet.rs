@@ -0,0 +1,310 @@
+//! HPET模块
+//!
+//! HPET（High Precision Event Timer）的寄存器通过MMIO访问，物理地址由ACPI的HPET表给出：
+//! - 主计数器（Main Counter）以固定的频率递增（周期由GCAP_ID给出，单位为fs），作为高精度的时钟源；
+//! - 每个定时器（Comparator）在主计数器等于比较值时触发中断，支持单次（One-shot）和周期（Periodic）模式；
+//! - 定时器的中断通过IO-APIC的GSI发送，每个定时器可以使用的GSI由其配置寄存器的高32位给出。
+//!
+//! 这里不使用Legacy Replacement（会占用IRQ0和IRQ8，使RTC无法产生中断），
+//! 而是按ISA中断对应的GSI设置定时器的路由，中断处理函数仍通过irq模块注册。
+//! 若定时器0可以路由到IRQ0，则由其代替PIT产生Timer中断。
+
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::{instructions::interrupts, PhysAddr};
+use crate::time::{self, ClockSource};
+use super::driver::acpi;
+use super::ioremap::{ioremap, CachePolicy, IoMem};
+use super::{apic, pic, pit};
+
+
+/// HPET寄存器偏移
+mod reg {
+    pub const GCAP_ID: usize = 0x000;
+    pub const GEN_CONF: usize = 0x010;
+    pub const MAIN_COUNTER: usize = 0x0f0;
+    pub const fn timer_conf(n: usize) -> usize { 0x100 + 0x20 * n }
+    pub const fn timer_comparator(n: usize) -> usize { 0x108 + 0x20 * n }
+}
+
+/// GCAP_ID：主计数器为64位
+const CAP_COUNTER_64: u64 = 1 << 13;
+/// GEN_CONF：使能主计数器
+const CONF_ENABLE: u64 = 1 << 0;
+
+/// 定时器配置：电平触发
+const TN_LEVEL: u64 = 1 << 1;
+/// 定时器配置：使能中断
+const TN_INT_ENABLE: u64 = 1 << 2;
+/// 定时器配置：周期模式
+const TN_PERIODIC: u64 = 1 << 3;
+/// 定时器配置：支持周期模式
+const TN_PERIODIC_CAP: u64 = 1 << 4;
+/// 定时器配置：周期模式下，下一次写比较值时设置周期
+const TN_SETVAL: u64 = 1 << 6;
+/// 定时器配置：中断路由（GSI）
+const TN_ROUTE_SHIFT: u64 = 9;
+const TN_ROUTE_MASK: u64 = 0x1f << TN_ROUTE_SHIFT;
+
+/// HPET寄存器区域的大小
+const HPET_MMIO_SIZE: usize = 0x400;
+
+/// ACPI HPET表中地址（GAS的address字段）的偏移
+const ACPI_HPET_ADDRESS: usize = 44;
+
+/// HPET错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// 定时器编号超出范围
+    BadTimer,
+    /// 定时器不能路由到该IRQ（或未使用IO-APIC）
+    RouteUnavailable,
+    /// 定时器不支持周期模式
+    PeriodicUnsupported,
+}
+
+/// 定时器模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// 经过指定的时间后触发一次
+    OneShot(Duration),
+    /// 每隔指定的时间触发一次
+    Periodic(Duration),
+}
+
+pub struct Hpet {
+    mmio: IoMem,
+    /// 主计数器的周期（fs）
+    period_fs: u64,
+    timers: usize,
+    counter_64: bool,
+    /// 保护定时器配置的读-改-写
+    lock: Mutex<()>,
+}
+
+impl Hpet {
+    /// 主计数器的值
+    pub fn counter(&self) -> u64 {
+        if self.counter_64 {
+            self.mmio.read::<u64>(reg::MAIN_COUNTER)
+        } else {
+            self.mmio.read::<u32>(reg::MAIN_COUNTER) as u64
+        }
+    }
+
+    /// 主计数器的频率（Hz）
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    /// 定时器数量
+    pub fn timers(&self) -> usize {
+        self.timers
+    }
+
+    /// 时间对应的计数值（至少为1）
+    fn cycles(&self, duration: Duration) -> u64 {
+        ((duration.as_nanos() * 1_000_000 / self.period_fs as u128) as u64).max(1)
+    }
+
+    /// 设置定时器n，中断发送到ISA中断irq
+    ///
+    /// 中断处理函数需要通过irq::register_irq注册（注册时才会使能该IRQ）。
+    pub fn set_timer(&self, n: usize, irq: u8, mode: TimerMode) -> Result<(), HpetError> {
+        if n >= self.timers {
+            return Err(HpetError::BadTimer);
+        }
+        if !pic::apic_enabled() {
+            return Err(HpetError::RouteUnavailable);
+        }
+        let gsi = apic::isa_irq_gsi(irq);
+        let conf_reg = reg::timer_conf(n);
+        let cmp_reg = reg::timer_comparator(n);
+        interrupts::without_interrupts(|| {
+            let _guard = self.lock.lock();
+            let conf = self.mmio.read::<u64>(conf_reg);
+            if gsi >= 32 || (conf >> 32) & (1 << gsi) == 0 {
+                return Err(HpetError::RouteUnavailable);
+            }
+            // 先关闭定时器，再设置比较值
+            let mut conf = conf & !(TN_INT_ENABLE | TN_PERIODIC | TN_LEVEL | TN_ROUTE_MASK);
+            self.mmio.write::<u64>(conf_reg, conf);
+            conf |= (gsi as u64) << TN_ROUTE_SHIFT | TN_INT_ENABLE;
+            match mode {
+                TimerMode::OneShot(delay) => {
+                    self.mmio.write::<u64>(conf_reg, conf);
+                    self.mmio.write::<u64>(cmp_reg, self.counter() + self.cycles(delay));
+                },
+                TimerMode::Periodic(period) => {
+                    if conf & TN_PERIODIC_CAP == 0 {
+                        return Err(HpetError::PeriodicUnsupported);
+                    }
+                    // TN_SETVAL时，第一次写入下一次触发的比较值，第二次写入周期
+                    let cycles = self.cycles(period);
+                    self.mmio.write::<u64>(conf_reg, conf | TN_PERIODIC | TN_SETVAL);
+                    self.mmio.write::<u64>(cmp_reg, self.counter() + cycles);
+                    self.mmio.write::<u64>(cmp_reg, cycles);
+                },
+            }
+            Ok(())
+        })
+    }
+
+    /// 关闭定时器n
+    pub fn stop_timer(&self, n: usize) -> Result<(), HpetError> {
+        if n >= self.timers {
+            return Err(HpetError::BadTimer);
+        }
+        interrupts::without_interrupts(|| {
+            let _guard = self.lock.lock();
+            let conf = self.mmio.read::<u64>(reg::timer_conf(n));
+            self.mmio.write::<u64>(reg::timer_conf(n), conf & !(TN_INT_ENABLE | TN_PERIODIC));
+        });
+        Ok(())
+    }
+}
+
+/// HPET主计数器的时钟源
+struct HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        HPET.get().map_or(0, Hpet::counter)
+    }
+
+    fn frequency(&self) -> u64 {
+        HPET.get().map_or(1, Hpet::frequency)
+    }
+
+    fn rating(&self) -> u32 {
+        // 低于不变的TSC，高于其它TSC
+        250
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// 获取HPET，没有HPET时返回None
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// 查找并初始化HPET
+///
+/// 需要在pic::init之后调用：注册为时钟源，并尽量使用定时器0代替PIT产生Timer中断。
+pub fn init() {
+    let table = match acpi::find_table(b"HPET") {
+        Some(table) => table,
+        None => {
+            println!("HPET: not present");
+            return;
+        },
+    };
+    let phys = unsafe {
+        core::ptr::read_unaligned(super::memory::phys_to_virt(table + ACPI_HPET_ADDRESS).as_ptr::<u64>())
+    };
+    let mmio = match ioremap(PhysAddr::new(phys), HPET_MMIO_SIZE, CachePolicy::Uncached) {
+        Ok(mmio) => mmio,
+        Err(e) => {
+            println!("HPET: failed to map registers: {:?}", e);
+            return;
+        },
+    };
+
+    let cap = mmio.read::<u64>(reg::GCAP_ID);
+    let hpet = Hpet {
+        period_fs: cap >> 32,
+        timers: ((cap >> 8) & 0x1f) as usize + 1,
+        counter_64: cap & CAP_COUNTER_64 != 0,
+        lock: Mutex::new(()),
+        mmio,
+    };
+    if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+        println!("HPET: invalid period {} fs", hpet.period_fs);
+        return;
+    }
+    // 关闭所有定时器，然后使能主计数器
+    for n in 0 .. hpet.timers {
+        let conf = hpet.mmio.read::<u64>(reg::timer_conf(n));
+        hpet.mmio.write::<u64>(reg::timer_conf(n), conf & !(TN_INT_ENABLE | TN_PERIODIC));
+    }
+    let conf = hpet.mmio.read::<u64>(reg::GEN_CONF);
+    hpet.mmio.write::<u64>(reg::GEN_CONF, conf | CONF_ENABLE);
+    let hpet = HPET.call_once(|| hpet);
+    println!("HPET: {} Hz, {} timers, {}-bit counter", hpet.frequency(), hpet.timers,
+        if hpet.counter_64 { 64 } else { 32 });
+
+    // 32位的计数器很快就会回绕，不作为时钟源
+    if hpet.counter_64 {
+        time::register_clock_source(&HpetClock);
+    }
+
+    // 定时器0代替PIT，PIT与HPET都连接到IRQ0，定时器0设置成功后停止PIT
+    let tick = time::ticks_to_duration(1);
+    if hpet.set_timer(0, pic::PicIRQ::Timer.as_isa(), TimerMode::Periodic(tick)).is_ok() {
+        pit::stop();
+        println!("HPET: timer 0 drives the {} Hz tick", time::HZ);
+    }
+}
+
+/// 使用主计数器校准其它计数器，没有HPET时返回None
+///
+/// 计时ms毫秒，返回read的计数值在这段时间内的增量换算成的频率。
+pub fn calibrate(ms: u32, mut read: impl FnMut() -> u64) -> Option<u64> {
+    let hpet = HPET.get()?;
+    let cycles = hpet.frequency() * ms as u64 / 1000;
+    let begin = hpet.counter();
+    let start = read();
+    while hpet.counter().wrapping_sub(begin) < cycles {
+        core::hint::spin_loop();
+    }
+    let end = read();
+    let elapsed = hpet.counter().wrapping_sub(begin);
+    Some(((end - start) as u128 * hpet.frequency() as u128 / elapsed as u128) as u64)
+}
+
+
+
+/// 主计数器递增，定时器可以触发单次中断
+#[test_case]
+fn test_hpet() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use super::irq;
+
+    let hpet = match hpet() {
+        Some(hpet) => hpet,
+        None => return,
+    };
+    let start = hpet.counter();
+    while hpet.counter() == start {
+        core::hint::spin_loop();
+    }
+    assert_eq!(hpet.set_timer(hpet.timers(), 0, TimerMode::OneShot(Duration::from_millis(1))),
+        Err(HpetError::BadTimer));
+
+    // 定时器1使用IRQ8（与RTC共享GSI，RTC未使能周期中断）；不支持该路由时跳过
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn handler(_irq: u8) {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    const IRQ: u8 = 8;
+    if hpet.timers() < 2 {
+        return;
+    }
+    irq::register_irq(IRQ, handler).unwrap();
+    if hpet.set_timer(1, IRQ, TimerMode::OneShot(Duration::from_millis(5))).is_err() {
+        irq::unregister_irq(IRQ, handler).unwrap();
+        return;
+    }
+    interrupts::enable();
+    while FIRED.load(Ordering::SeqCst) == 0 {
+        x86_64::instructions::hlt();
+    }
+    interrupts::disable();
+    hpet.stop_timer(1).unwrap();
+    irq::unregister_irq(IRQ, handler).unwrap();
+}
@@ -1,68 +1,67 @@
 //! IDT模块
 //!
-//! 设置CPU Exception的中断服务例程；
+//! 设置CPU Exception（见trap模块）和外部中断的中断服务例程；
 //! 实际操作为，使用`lidt`指令，将数据IDT的地址和长度保存在IDTR寄存器。
 //! IDT在数据上来说，本质是一个uint8[256][16]数组，每16bytes是一个Entry。
 
 use super::gdt;
-use super::{pic, pic::PicIRQ};
+use super::{apic, irq, trap};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{
-    InterruptDescriptorTable,
-    InterruptStackFrame,
-    PageFaultErrorCode,
-};
+use x86_64::structures::idt::InterruptDescriptorTable;
 
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        // 设置exception中断例程
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // 设置exception中断例程（均由trap模块处理）
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
+            idt.divide_error.set_handler_addr(trap::handler_addr(0));
+            idt.debug.set_handler_addr(trap::handler_addr(1));
+            idt.non_maskable_interrupt.set_handler_addr(trap::handler_addr(2))
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.breakpoint.set_handler_addr(trap::handler_addr(3));
+            idt.overflow.set_handler_addr(trap::handler_addr(4));
+            idt.bound_range_exceeded.set_handler_addr(trap::handler_addr(5));
+            idt.invalid_opcode.set_handler_addr(trap::handler_addr(6));
+            idt.device_not_available.set_handler_addr(trap::handler_addr(7));
+            idt.double_fault.set_handler_addr(trap::handler_addr(8))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.invalid_tss.set_handler_addr(trap::handler_addr(10));
+            idt.segment_not_present.set_handler_addr(trap::handler_addr(11));
+            idt.stack_segment_fault.set_handler_addr(trap::handler_addr(12));
+            idt.general_protection_fault.set_handler_addr(trap::handler_addr(13));
+            idt.page_fault.set_handler_addr(trap::handler_addr(14));
+            idt.x87_floating_point.set_handler_addr(trap::handler_addr(16));
+            idt.alignment_check.set_handler_addr(trap::handler_addr(17));
+            idt.machine_check.set_handler_addr(trap::handler_addr(18))
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            idt.simd_floating_point.set_handler_addr(trap::handler_addr(19));
+            idt.virtualization.set_handler_addr(trap::handler_addr(20));
+            idt.vmm_communication_exception.set_handler_addr(trap::handler_addr(29));
+            idt.security_exception.set_handler_addr(trap::handler_addr(30));
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[PicIRQ::Timer.as_usize()].set_handler_fn(pic::timer_handler);
-        idt[PicIRQ::Keyboard.as_usize()].set_handler_fn(pic::keyboard_handler);
+        // ISA中断使用与exception相同的入口，由irq模块分发给注册的处理函数
+        for irq in 0 .. irq::IRQ_COUNT as u8 {
+            let vector = irq::vector(irq);
+            unsafe { idt[vector as usize].set_handler_addr(trap::handler_addr(vector)); }
+        }
+        unsafe {
+            idt[apic::TIMER_VECTOR as usize].set_handler_addr(trap::handler_addr(apic::TIMER_VECTOR));
+            idt[apic::WAKEUP_VECTOR as usize].set_handler_addr(trap::handler_addr(apic::WAKEUP_VECTOR));
+            idt[apic::CALL_VECTOR as usize].set_handler_addr(trap::handler_addr(apic::CALL_VECTOR));
+        }
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_handler);
         idt
     };
 }
 
+/// 加载IDT（每个CPU Core都需要加载，IDT由所有CPU共享）
 pub fn init() {
     IDT.load();
 }
 
 
-/// Breakpoint(No = 3)可以暂停程序执行，常用于调试；
-/// 使用指令int 3（3为Breakpoint的中断号）可以触发Breakpoint。
-/// x86_64 crate中可以使用instructions::interrupts::int3()测试。
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: Breakpoint\n{:#?}", stack_frame);
-}
-
-/// Page Fault(No = 14)，当访问的地址没有对应的物理地址，会触发Page Fault。
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: Page Fault");
-    println!("Accessed Address: {:?}", Cr2::read()); // CR2寄存器保存了引起中断的Virtual Address
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-
-    // 没有为Virtual Address映射Physical Address前，代码应当停止运行
-    super::hlt_loop();
-}
-
-
-/// Double Fault(No = 8)异常处理
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
-    panic!("EXCEPTION: Double Fault({})\n{:#?}", error_code, stack_frame);
-}
-
-
 
 #[test_case]
 fn test_breakpoint() {
@@ -14,11 +14,11 @@ pub fn putfmt(args: Arguments) {
     });
 
     #[cfg(test)]
-    {
+    interrupts::without_interrupts(|| {
         use super::driver::serial;
         serial::SERIAL1
             .lock()
             .write_fmt(args)
             .expect("Failed printing to serial(0x3F8)");
-    }
+    });
 }
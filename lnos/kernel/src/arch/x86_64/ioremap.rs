@@ -0,0 +1,226 @@
+//! MMIO映射模块
+//!
+//! ioremap从vmalloc区域分配虚拟地址，将设备的物理地址映射到该地址，并按CachePolicy设置缓存属性。
+//!
+//! 页表Entry的缓存属性由PAT、PCD、PWT三位选择IA32_PAT中的一项（PAT0~PAT7），
+//! 初始化时将IA32_PAT重新设置为：
+//! - PAT0 = WB（默认）；
+//! - PAT1 = WC（原为WT），对应只设置PWT；
+//! - PAT3 = UC，对应设置PCD和PWT；
+//! 这样只需要PCD和PWT就可以选择所有的CachePolicy，4KiB和2MiB的page使用相同的flags。
+
+use core::{mem, ptr};
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+use super::memory::PageTableImpl;
+use super::vmalloc;
+
+
+/// IA32_PAT寄存器
+const IA32_PAT: u32 = 0x277;
+/// PAT0~PAT7：WB, WC, UC-, UC, WB, WT, UC-, UC
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
+/// 缓存属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// 回写，用于普通内存
+    WriteBack,
+    /// 写合并，用于framebuffer等
+    WriteCombining,
+    /// 不缓存，用于设备寄存器
+    Uncached,
+}
+
+impl CachePolicy {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CachePolicy::WriteBack => PageTableFlags::empty(),
+            CachePolicy::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CachePolicy::Uncached => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// ioremap错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoremapError {
+    /// 长度为0或地址溢出
+    BadRange,
+    /// vmalloc区域没有足够的虚拟地址
+    NoVirtualSpace,
+    /// 没有可用的物理内存（用于中间页表）
+    OutOfMemory,
+}
+
+/// 从vmalloc区域分配虚拟地址
+fn reserve(size: u64, align: u64) -> Result<u64, IoremapError> {
+    vmalloc::reserve(size as usize, align)
+        .map(|addr| addr.as_u64())
+        .map_err(|_| IoremapError::NoVirtualSpace)
+}
+
+/// 设置IA32_PAT（每个CPU Core都需要设置）
+pub fn init() {
+    // CPUID.01H:EDX的bit16
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    if cpuid.edx & (1 << 16) == 0 {
+        println!("WARNING: PAT not supported, write-combining falls back to write-through");
+        return;
+    }
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        // 修改PAT后需要刷新缓存和TLB
+        core::arch::asm!("wbinvd", options(nostack));
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// 映射的MMIO区域
+///
+/// 通过read和write按类型访问寄存器，访问均为volatile；Drop时取消映射。
+pub struct IoMem {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    /// 映射的虚拟地址范围
+    map_start: u64,
+    map_size: u64,
+}
+
+impl IoMem {
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn check<T>(&self, offset: usize) {
+        assert!(offset + mem::size_of::<T>() <= self.len, "MMIO access out of range: {:#x}", offset);
+        assert!((self.virt.as_u64() as usize + offset) % mem::align_of::<T>() == 0, "unaligned MMIO access: {:#x}", offset);
+    }
+
+    /// 读取offset处的寄存器
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.check::<T>(offset);
+        unsafe { ptr::read_volatile((self.virt + offset).as_ptr::<T>()) }
+    }
+
+    /// 写入offset处的寄存器
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.check::<T>(offset);
+        unsafe { ptr::write_volatile((self.virt + offset).as_mut_ptr::<T>(), value) }
+    }
+}
+
+impl Drop for IoMem {
+    fn drop(&mut self) {
+        // 设备内存不属于Frame分配器，不需要释放
+        PageTableImpl::active().unmap_range(VirtAddr::new(self.map_start), self.map_size);
+        vmalloc::release(VirtAddr::new(self.map_start)).expect("ioremap range not found");
+    }
+}
+
+/// 将物理地址[phys, phys + len)映射到内核空间
+///
+/// 物理地址和虚拟地址均按2MiB对齐的部分使用2MiB的page映射。
+pub fn ioremap(phys: PhysAddr, len: usize, policy: CachePolicy) -> Result<IoMem, IoremapError> {
+    let end = phys.as_u64().checked_add(len as u64).filter(|_| len > 0).ok_or(IoremapError::BadRange)?;
+    let phys_start = phys.align_down(Size4KiB::SIZE).as_u64();
+    let phys_end = PhysAddr::new(end).align_up(Size4KiB::SIZE).as_u64();
+
+    // 大于2MiB的区域，虚拟地址与物理地址按2MiB取余相同，以便使用2MiB的page
+    let (map_start, map_size, virt_start) = if phys_end - phys_start >= Size2MiB::SIZE {
+        let skew = phys_start % Size2MiB::SIZE;
+        let map_size = phys_end - phys_start + skew;
+        let map_start = reserve(map_size, Size2MiB::SIZE)?;
+        (map_start, map_size, map_start + skew)
+    } else {
+        let map_size = phys_end - phys_start;
+        let map_start = reserve(map_size, Size4KiB::SIZE)?;
+        (map_start, map_size, map_start)
+    };
+
+    // 映射失败时，drop(mem)取消已经映射的部分并释放虚拟地址
+    let mem = IoMem {
+        virt: VirtAddr::new(virt_start + (phys.as_u64() - phys_start)),
+        phys,
+        len,
+        map_start,
+        map_size,
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | policy.flags();
+    let mut pg = PageTableImpl::active();
+    let mut addr = phys_start;
+    while addr < phys_end {
+        let virt = VirtAddr::new(virt_start + (addr - phys_start));
+        let mapped = if addr % Size2MiB::SIZE == 0 && phys_end - addr >= Size2MiB::SIZE {
+            let result = pg.try_map(Page::<Size2MiB>::containing_address(virt), PhysFrame::containing_address(PhysAddr::new(addr)), flags);
+            addr += Size2MiB::SIZE;
+            result.is_ok()
+        } else {
+            let result = pg.try_map(Page::<Size4KiB>::containing_address(virt), PhysFrame::containing_address(PhysAddr::new(addr)), flags);
+            addr += Size4KiB::SIZE;
+            result.is_ok()
+        };
+        if !mapped {
+            return Err(IoremapError::OutOfMemory);
+        }
+    }
+    Ok(mem)
+}
+
+/// 取消ioremap的映射
+pub fn iounmap(mem: IoMem) {
+    drop(mem);
+}
+
+
+
+/// 以4KiB和2MiB的page映射Local APIC的寄存器
+///
+/// 只映射设备内存（与apic模块的映射同为Uncached），不能以其它缓存属性映射普通内存：
+/// 直接映射中的同一Frame为WB，不同的缓存属性同时存在时行为未定义。
+#[test_case]
+fn test_ioremap() {
+    use super::driver::acpi;
+
+    assert_eq!(CachePolicy::WriteBack.flags(), PageTableFlags::empty());
+    assert_eq!(CachePolicy::WriteCombining.flags(), PageTableFlags::WRITE_THROUGH);
+    let madt = match acpi::madt() {
+        Some(madt) if super::pic::apic_enabled() => madt,
+        _ => return,
+    };
+    let phys = PhysAddr::new(madt.lapic_addr);
+    let id = x86_64::instructions::interrupts::without_interrupts(|| super::apic::local_apic().id());
+
+    let small = ioremap(phys, 0x400, CachePolicy::Uncached).unwrap();
+    assert_eq!(small.read::<u32>(0x20) >> 24, id);
+    assert_eq!(ioremap(phys, 0, CachePolicy::Uncached).err(), Some(IoremapError::BadRange));
+
+    let base = phys.align_down(Size2MiB::SIZE);
+    let large = ioremap(base, Size2MiB::SIZE as usize, CachePolicy::Uncached).unwrap();
+    assert_eq!(large.read::<u32>((phys - base) as usize + 0x20) >> 24, id);
+
+    let pg = PageTableImpl::active();
+    let (_, flags, size) = pg.translate(large.virt()).unwrap();
+    assert_eq!(size, Size2MiB::SIZE);
+    assert!(flags.contains(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE));
+    let (mapped, flags, size) = pg.translate(small.virt()).unwrap();
+    assert_eq!((mapped, size), (phys, Size4KiB::SIZE));
+    assert!(flags.contains(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE));
+
+    let virt = small.virt();
+    iounmap(small);
+    drop(large);
+    assert!(pg.translate(virt).is_none());
+}
@@ -0,0 +1,180 @@
+//! IPI模块
+//!
+//! 处理器间中断（Inter-Processor Interrupt）通过Local APIC的ICR发送：
+//! - send：向一个CPU、所有CPU或除当前CPU以外的所有CPU发送指定中断号的IPI；
+//! - call（cross-call）：在目标CPU上执行闭包，并等待全部执行完毕；
+//!   请求放入目标CPU的mailbox，再发送CALL_VECTOR的IPI，由目标CPU在中断处理中执行。
+//!
+//! call在关中断时执行，等待期间会处理当前CPU自己的mailbox，所以两个CPU同时互相call不会死锁；
+//! 但调用者不能持有其它CPU会在关中断时等待的锁（如堆分配器的锁），否则目标CPU无法响应。
+//!
+//! TLB shootdown（见memory::flush_tlb）基于call实现。
+
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use spin::Once;
+use x86_64::instructions::{interrupts, tlb};
+use super::cpu::{self, MAX_CPUS};
+use super::{apic, pic};
+
+
+/// IPI的目标
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiTarget {
+    /// 编号为id的CPU
+    Cpu(usize),
+    /// 所有CPU（包括当前CPU）
+    All,
+    /// 除当前CPU以外的所有CPU
+    AllButSelf,
+}
+
+impl IpiTarget {
+    /// 编号为id的CPU是否是目标（this为当前CPU的编号）
+    fn contains(self, id: usize, this: usize) -> bool {
+        match self {
+            IpiTarget::Cpu(target) => target == id,
+            IpiTarget::All => true,
+            IpiTarget::AllButSelf => id != this,
+        }
+    }
+}
+
+/// 发送中断号为vector的IPI
+///
+/// 不使用APIC时只有一个CPU，不做任何处理；目标CPU不存在时忽略。
+pub fn send(target: IpiTarget, vector: u8) {
+    if !pic::apic_enabled() {
+        return;
+    }
+    let lapic = apic::local_apic();
+    match target {
+        IpiTarget::Cpu(id) => {
+            if let Some(cpu) = cpu::get(id) {
+                lapic.send_fixed(cpu.apic_id(), vector);
+            }
+        },
+        IpiTarget::All => lapic.send_broadcast(vector, true),
+        IpiTarget::AllButSelf => lapic.send_broadcast(vector, false),
+    }
+}
+
+/// cross-call请求，位于发起CPU的栈上
+///
+/// 发起CPU等待pending变为0（所有目标CPU执行完毕）后才返回，所以目标CPU访问时func和request都有效。
+struct CallRequest {
+    func: *const (dyn Fn() + Sync),
+    pending: AtomicUsize,
+}
+
+/// mailbox中的请求
+struct CallPtr(*const CallRequest);
+
+unsafe impl Send for CallPtr {}
+
+/// 每个CPU的mailbox的容量（每个CPU同一时刻最多发起一个请求，嵌套的call除外）
+const MAILBOX_SIZE: usize = MAX_CPUS * 2;
+
+/// 每个CPU的mailbox，下标为CPU编号；初始化后该CPU才会成为call的目标
+static MAILBOXES: [Once<ArrayQueue<CallPtr>>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: Once<ArrayQueue<CallPtr>> = Once::new();
+    [NONE; MAX_CPUS]
+};
+
+/// 初始化当前CPU的mailbox（每个CPU在开中断之前调用）
+///
+/// 在此之前的TLB shootdown不会发送到当前CPU，所以初始化后刷新整个TLB。
+pub fn init() {
+    MAILBOXES[cpu::id()].call_once(|| ArrayQueue::new(MAILBOX_SIZE));
+    fence(Ordering::SeqCst);
+    tlb::flush_all();
+}
+
+/// 在target指定的CPU上执行f，等待全部执行完毕后返回
+///
+/// f在目标CPU上关中断执行，不能阻塞；当前CPU是目标时直接调用f。
+/// 还没有初始化mailbox的CPU（正在启动的AP）不会执行f。
+pub fn call(target: IpiTarget, f: &(dyn Fn() + Sync)) {
+    interrupts::without_interrupts(|| {
+        let this = cpu::id();
+        // 调用者之前的修改（如页表）需要在检查目标之前对其它CPU可见（见init）
+        fence(Ordering::SeqCst);
+        let mut targets = [false; MAX_CPUS];
+        let mut count = 0;
+        for (id, mailbox) in MAILBOXES.iter().enumerate() {
+            if id != this && target.contains(id, this) && mailbox.get().is_some() {
+                targets[id] = true;
+                count += 1;
+            }
+        }
+
+        let request = CallRequest {
+            // 返回之前一直等待，所以可以擦除f的生命周期
+            func: unsafe { core::mem::transmute::<&(dyn Fn() + Sync), &'static (dyn Fn() + Sync)>(f) },
+            pending: AtomicUsize::new(count),
+        };
+        for id in (0 .. MAX_CPUS).filter(|&id| targets[id]) {
+            let mailbox = MAILBOXES[id].get().unwrap();
+            let mut ptr = CallPtr(&request);
+            // mailbox已满时，处理自己的请求，等待目标CPU处理
+            while let Err(p) = mailbox.push(ptr) {
+                ptr = p;
+                handle_calls();
+                core::hint::spin_loop();
+            }
+            send(IpiTarget::Cpu(id), apic::CALL_VECTOR);
+        }
+
+        if target.contains(this, this) {
+            f();
+        }
+        while request.pending.load(Ordering::Acquire) != 0 {
+            handle_calls();
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// 执行当前CPU的mailbox中的请求
+fn handle_calls() {
+    let mailbox = match MAILBOXES[cpu::id()].get() {
+        Some(mailbox) => mailbox,
+        None => return,
+    };
+    while let Some(CallPtr(request)) = mailbox.pop() {
+        let request = unsafe { &*request };
+        unsafe { (*request.func)() };
+        // 之后不能再访问request，发起CPU可能已经返回
+        request.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// cross-call IPI（由trap_dispatch调用，已关中断）
+pub(super) fn call_interrupt() {
+    handle_calls();
+    apic::eoi();
+}
+
+
+
+/// call在所有已经启动的CPU上各执行一次
+#[test_case]
+fn test_call() {
+    let counter = AtomicUsize::new(0);
+    call(IpiTarget::All, &|| {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(counter.load(Ordering::SeqCst), cpu::count());
+
+    counter.store(0, Ordering::SeqCst);
+    call(IpiTarget::AllButSelf, &|| {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(counter.load(Ordering::SeqCst), cpu::count() - 1);
+
+    let this = cpu::id();
+    let ran_on = AtomicUsize::new(usize::MAX);
+    call(IpiTarget::Cpu(this), &|| ran_on.store(cpu::id(), Ordering::SeqCst));
+    assert_eq!(ran_on.load(Ordering::SeqCst), this);
+}
@@ -0,0 +1,145 @@
+//! 中断注册模块
+//!
+//! ISA中断IRQ0~15使用中断号32~47（见pic模块），每个中断号的入口与CPU Exception相同（trap模块的__trap_N），
+//! trap_dispatch将这些中断交给dispatch处理：
+//! - 依次调用该IRQ注册的所有处理函数（多个设备可以共享一个IRQ）；
+//! - 统计每个IRQ的中断次数；
+//! - 自动通知EOI，然后检查是否需要抢占当前线程。
+//!
+//! 驱动通过register_irq和unregister_irq在运行时注册处理函数，不需要修改IDT和pic模块；
+//! 注册第一个处理函数时使能该IRQ，注销最后一个时屏蔽。
+//! 处理函数在中断中执行（已关中断），不能阻塞。
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::pic;
+
+
+/// IRQ数量
+pub const IRQ_COUNT: usize = 16;
+/// IRQ0对应的中断号
+pub const IRQ_BASE: u8 = pic::PIC_1_OFFSET;
+/// 每个IRQ最多可以注册的处理函数数量
+pub const MAX_SHARED: usize = 4;
+
+/// 中断处理函数，参数为IRQ号
+pub type IrqHandler = fn(irq: u8);
+
+/// 中断注册错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// IRQ号超出范围
+    BadIrq,
+    /// 该IRQ的处理函数已满
+    TooManyHandlers,
+    /// 处理函数已经注册
+    AlreadyRegistered,
+    /// 处理函数没有注册
+    NotRegistered,
+}
+
+/// 每个IRQ的处理函数，只能在关中断时访问
+static HANDLERS: Mutex<[[Option<IrqHandler>; MAX_SHARED]; IRQ_COUNT]> = Mutex::new([[None; MAX_SHARED]; IRQ_COUNT]);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// 每个IRQ的中断次数
+static COUNTS: [AtomicU64; IRQ_COUNT] = [ZERO; IRQ_COUNT];
+
+/// IRQ对应的中断号
+pub fn vector(irq: u8) -> u8 {
+    IRQ_BASE + irq
+}
+
+/// 为irq注册处理函数
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::BadIrq);
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        if line.iter().flatten().any(|&h| h as usize == handler as usize) {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let first = line.iter().all(|h| h.is_none());
+        let slot = line.iter_mut().find(|h| h.is_none()).ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(handler);
+        if first {
+            pic::unmask(irq);
+        }
+        Ok(())
+    })
+}
+
+/// 注销irq的处理函数
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::BadIrq);
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        let slot = line.iter_mut()
+            .find(|h| h.map_or(false, |h| h as usize == handler as usize))
+            .ok_or(IrqError::NotRegistered)?;
+        *slot = None;
+        if line.iter().all(|h| h.is_none()) {
+            pic::mask(irq);
+        }
+        Ok(())
+    })
+}
+
+/// irq的中断次数
+pub fn irq_count(irq: u8) -> u64 {
+    COUNTS[irq as usize].load(Ordering::Relaxed)
+}
+
+/// 处理irq（由trap_dispatch调用，已关中断）
+pub(super) fn dispatch(irq: u8) {
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    // 复制处理函数后释放锁，处理函数中可以注册和注销
+    let handlers = HANDLERS.lock()[irq as usize];
+    for handler in handlers.iter().flatten() {
+        handler(irq);
+    }
+    pic::end_of_interrupt(irq);
+    // 需要在通知EOI之后调用，否则切换到其它线程后，无法再响应中断
+    crate::kthread::preempt();
+}
+
+
+
+/// 共享同一个IRQ的处理函数都会被调用
+#[test_case]
+fn test_register_irq() {
+    use core::sync::atomic::AtomicUsize;
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+    fn handler_a(_irq: u8) {
+        HITS.fetch_add(1, Ordering::SeqCst);
+    }
+    fn handler_b(_irq: u8) {
+        HITS.fetch_add(10, Ordering::SeqCst);
+    }
+
+    // IRQ5在qemu中没有设备使用，通过int指令触发
+    const IRQ: u8 = 5;
+    register_irq(IRQ, handler_a).unwrap();
+    register_irq(IRQ, handler_b).unwrap();
+    assert_eq!(register_irq(IRQ, handler_a), Err(IrqError::AlreadyRegistered));
+    assert_eq!(register_irq(IRQ_COUNT as u8, handler_a), Err(IrqError::BadIrq));
+
+    let count = irq_count(IRQ);
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(HITS.load(Ordering::SeqCst), 11);
+    assert_eq!(irq_count(IRQ), count + 1);
+
+    unregister_irq(IRQ, handler_b).unwrap();
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(HITS.load(Ordering::SeqCst), 12);
+    unregister_irq(IRQ, handler_a).unwrap();
+    assert_eq!(unregister_irq(IRQ, handler_a), Err(IrqError::NotRegistered));
+    assert_eq!(irq_count(IRQ), count + 2);
+}
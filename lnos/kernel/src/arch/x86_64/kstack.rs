@@ -0,0 +1,195 @@
+//! 内核栈模块
+//!
+//! 内核栈映射在内核空间的KSTACK区域中，区域按KSTACK_SLOT_SIZE划分为slot，每个slot存放一个栈：
+//! - slot的最低一个page为guard page，不映射，栈从slot的顶端向下增长；
+//! - 栈溢出时访问guard page触发Page Fault，由guard_hit根据地址找到对应的栈，报告栈的名称。
+//!
+//! 栈溢出时CPU无法在同一个栈上压入Page Fault的中断栈帧，会升级为Double Fault（使用IST的栈），
+//! 所以Page Fault和Double Fault的处理函数都需要检查guard_hit。
+//! 这两个处理函数可能在持有锁时被调用，所以栈表使用固定大小的表，不使用堆内存。
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+use super::memory::{GlobalFrameAllocator, PageTableImpl};
+
+
+/// KSTACK区域的起始地址
+pub const KSTACK_START: u64 = 0xffff_f000_0000_0000;
+/// 每个slot的大小（包含guard page）
+pub const KSTACK_SLOT_SIZE: usize = 0x1000 * 16;
+/// 最多可以同时存在的内核栈数量
+pub const MAX_KSTACKS: usize = 512;
+
+const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+/// 栈名称的最大长度，超过的部分被截断
+const NAME_LEN: usize = 32;
+
+/// 内核栈分配错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// 大小为0，或超过了slot的大小
+    BadSize,
+    /// 栈表已满
+    TableFull,
+    /// 没有可用的物理内存
+    OutOfMemory,
+}
+
+/// 不使用堆内存保存的栈名称
+#[derive(Clone, Copy)]
+pub struct StackName {
+    buf: [u8; NAME_LEN],
+    len: usize,
+}
+
+impl StackName {
+    fn new(name: &str) -> Self {
+        let mut len = name.len().min(NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut buf = [0u8; NAME_LEN];
+        buf[.. len].copy_from_slice(&name.as_bytes()[.. len]);
+        StackName { buf, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[.. self.len]).unwrap_or("?")
+    }
+}
+
+impl fmt::Display for StackName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for StackName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// 栈表中的Entry，记录slot中映射的大小和栈的名称
+#[derive(Clone, Copy)]
+struct Slot {
+    size: usize,
+    name: StackName,
+}
+
+/// 栈表只能在关中断时访问（Page Fault中也会访问）
+static SLOTS: Mutex<[Option<Slot>; MAX_KSTACKS]> = Mutex::new([None; MAX_KSTACKS]);
+
+fn slot_base(index: usize) -> u64 {
+    KSTACK_START + (index * KSTACK_SLOT_SIZE) as u64
+}
+
+/// 带guard page的内核栈，drop时取消映射并释放Frame
+pub struct KernelStack {
+    index: usize,
+    size: usize,
+}
+
+impl KernelStack {
+    /// 分配size大小（按page向上对齐）的内核栈
+    pub fn new(size: usize, name: &str) -> Result<Self, StackError> {
+        let size = (size + Size4KiB::SIZE as usize - 1) & !(Size4KiB::SIZE as usize - 1);
+        if size == 0 || size > KSTACK_SLOT_SIZE - GUARD_SIZE as usize {
+            return Err(StackError::BadSize);
+        }
+        let index = interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let index = slots.iter().position(|s| s.is_none()).ok_or(StackError::TableFull)?;
+            slots[index] = Some(Slot { size, name: StackName::new(name) });
+            Ok(index)
+        })?;
+
+        // 从slot的顶端向下映射，guard page与栈底之间的部分也不映射
+        let stack = KernelStack { index, size };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut pg = PageTableImpl::active();
+        let first = Page::<Size4KiB>::containing_address(stack.bottom());
+        for k in 0 .. (size as u64 / Size4KiB::SIZE) {
+            // 分配失败（包括中间页表）时，drop(stack)会释放已经映射的page和slot
+            let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().ok_or(StackError::OutOfMemory)?;
+            if pg.try_map(first + k, frame, flags).is_err() {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                return Err(StackError::OutOfMemory);
+            }
+        }
+        Ok(stack)
+    }
+
+    /// 栈顶（最高地址，不包含）
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(slot_base(self.index + 1))
+    }
+
+    /// 栈底（最低地址）
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.size as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut pg = PageTableImpl::active();
+        for (phys, _, _) in pg.unmap_range(self.bottom(), self.size as u64) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)) };
+        }
+        interrupts::without_interrupts(|| SLOTS.lock()[self.index] = None);
+    }
+}
+
+/// addr是否位于某个内核栈的guard page中（栈底以下未映射的部分），返回该栈的名称
+///
+/// 在Page Fault和Double Fault中调用，栈表的锁若被持有则返回None。
+pub fn guard_hit(addr: VirtAddr) -> Option<StackName> {
+    let addr = addr.as_u64();
+    if addr < KSTACK_START || addr >= slot_base(MAX_KSTACKS) {
+        return None;
+    }
+    let index = ((addr - KSTACK_START) / KSTACK_SLOT_SIZE as u64) as usize;
+    let slot = SLOTS.try_lock()?[index]?;
+    if addr < slot_base(index + 1) - slot.size as u64 {
+        Some(slot.name)
+    } else {
+        None
+    }
+}
+
+
+
+/// 栈的page均已映射，栈底以下为guard page
+#[test_case]
+fn test_kernel_stack() {
+    let stack = KernelStack::new(0x3000, "test stack").unwrap();
+    assert_eq!(stack.top() - stack.bottom(), 0x3000);
+    unsafe {
+        (stack.top() - 8u64).as_mut_ptr::<u64>().write_volatile(1);
+        stack.bottom().as_mut_ptr::<u64>().write_volatile(2);
+    }
+
+    let pg = PageTableImpl::active();
+    assert!(pg.translate(stack.bottom() - 1u64).is_none());
+    assert_eq!(guard_hit(stack.bottom() - 8u64).map(|n| n.as_str() == "test stack"), Some(true));
+    assert!(guard_hit(stack.bottom()).is_none());
+    assert_eq!(KernelStack::new(KSTACK_SLOT_SIZE, "too large").err(), Some(StackError::BadSize));
+
+    // 释放时取消映射所有page（其它CPU可能同时分配Frame，所以不检查全局的统计信息）
+    let bottom = stack.bottom();
+    assert!((0 .. 3u64).all(|k| pg.translate(bottom + k * Size4KiB::SIZE).is_some()));
+    drop(stack);
+    assert!((0 .. 3u64).all(|k| pg.translate(bottom + k * Size4KiB::SIZE).is_none()));
+    assert!(guard_hit(bottom - 8u64).is_none());
+}
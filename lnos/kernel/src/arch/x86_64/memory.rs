@@ -9,25 +9,58 @@
 //! 为VirtAddr或PhysAddr使用（类似于c中，将usize赋值给指针变量）。
 //! memory_map保存了内存的起始地址、类型的等信息。
 //!
+//! 虚拟地址空间的布局（物理内存映射和内核栈的地址在Cargo.toml的package.metadata.bootloader中设置）：
+//! - L4[0]：kernel代码和数据，以及bootloader的恒等映射；
+//! - L4[1..256)：用户空间，每个AddressSpace独立；
+//! - L4[256..512)：内核空间（物理内存映射、堆、vmalloc区域、内核栈等），所有AddressSpace共享；
+//!
+//! 内核空间的页表被所有CPU共享，PageTableImpl修改页表（map、unmap、update_flags）时持有PAGE_TABLE_LOCK，
+//! 防止多个CPU同时新建或修改同一个页表；TLB shootdown在释放锁之后进行（见ipi模块）。
+//!
 
+use alloc::vec::Vec;
 use spin::Mutex;
-use bitmap_allocator::BitAlloc;
 use bootloader::{BootInfo, bootinfo::MemoryRegionType};
 use x86_64::{
     instructions::interrupts,
     structures::paging::*,
+    structures::paging::mapper::MapToError,
     VirtAddr, PhysAddr,
 };
+use super::buddy::{BuddyAllocator, MAX_ORDER};
 
 
-type FrameBitAlloc = bitmap_allocator::BitAlloc64K; // 64*1024*4K=256M
-
-/// 通过bitmap可用的物理内存Frame进行管理
-static FRAME_ALLOCATOR: Mutex<FrameBitAlloc> = Mutex::new(FrameBitAlloc::DEFAULT);
+/// 通过Buddy分配器对可用的物理内存Frame进行管理
+static FRAME_ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
 
 /// 内存映射偏移地址
 static mut PHYS_MEM_OFS: u64 = 0x0;
 
+/// 内核的L4页表（bootloader创建的页表）
+static mut KERNEL_L4: Option<PhysFrame> = None;
+
+/// 为AP启动代码保留的1MiB以下的Frame
+static mut TRAMPOLINE_FRAME: Option<PhysFrame> = None;
+
+/// 1MiB以下的Frame数量（实模式可以访问的范围）
+const LOW_MEMORY_FRAMES: u64 = 0x100;
+
+/// 内核空间在L4中的起始Entry
+pub const KERNEL_L4_START: usize = 256;
+
+/// 修改页表时持有的锁（只在关中断时持有，见with_page_table_lock）
+static PAGE_TABLE_LOCK: Mutex<()> = Mutex::new(());
+
+/// 持有PAGE_TABLE_LOCK执行f
+///
+/// f中不能等待其它CPU（如TLB shootdown），也不能访问可能触发Page Fault的内存。
+fn with_page_table_lock<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let _guard = PAGE_TABLE_LOCK.lock();
+        f()
+    })
+}
+
 /// 内存管理初始化
 ///
 /// 在调用memory::init后，才能使用PageTableImpl、GlobalFrameAllocator等；
@@ -43,18 +76,94 @@ pub fn init(boot_info: &'static BootInfo) {
 
     unsafe {
         PHYS_MEM_OFS = boot_info.physical_memory_offset;
+        // 使能NO_EXECUTE（页表中的NX位），用户程序的数据段和栈不可执行
+        use x86_64::registers::model_specific::{Efer, EferFlags};
+        Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
     }
 
     // 初始化Frame分配器，标记可用内存地址；
     // 此时还没有使能中断，所以使用FRAME_ALLOCATOR时，不用关中断；
+    let usable = || phys_mem_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+    let frames = usable().map(|r| r.range.end_frame_number as usize).max().unwrap_or(0);
+    // 从第一个足够大的可用区域的开头划出Buddy分配器的meta
+    let meta_frames = (BuddyAllocator::meta_size(frames) + Size4KiB::SIZE as usize - 1) / Size4KiB::SIZE as usize;
+    let meta_start = usable()
+        .find(|r| (r.range.end_frame_number - r.range.start_frame_number) as usize >= meta_frames)
+        .expect("no memory for frame allocator")
+        .range.start_frame_number as usize;
+    let meta_end = meta_start + meta_frames;
+    // 从1MiB以下的可用内存中保留一个Frame，作为AP启动代码的地址（见smp模块）
+    let trampoline = usable()
+        .flat_map(|r| r.range.start_frame_number.max(1) as usize .. r.range.end_frame_number.min(LOW_MEMORY_FRAMES) as usize)
+        .find(|&f| f < meta_start || f >= meta_end);
+
     let mut fa = FRAME_ALLOCATOR.lock();
-    for i in phys_mem_map
-                .iter()
-                .filter(|r| r.region_type == MemoryRegionType::Usable)
-                .into_iter() {
+    unsafe {
+        fa.init(PhysAddr::new(meta_start as u64 * Size4KiB::SIZE), frames);
+        TRAMPOLINE_FRAME = trampoline.map(|f| PhysFrame::containing_address(PhysAddr::new(f as u64 * Size4KiB::SIZE)));
+    }
+    let mut add_range = |start: usize, end: usize| match trampoline {
+        Some(f) if start <= f && f < end => {
+            fa.add_range(start, f);
+            fa.add_range(f + 1, end);
+        },
+        _ => fa.add_range(start, end),
+    };
+    for i in usable() {
         // bootloader已经将Usable的内存按4K对齐了，可以直接标记
-        fa.insert((i.range.start_frame_number as usize) .. (i.range.end_frame_number as usize));
+        let (start, end) = (i.range.start_frame_number as usize, i.range.end_frame_number as usize);
+        if start <= meta_start && meta_end <= end {
+            add_range(start, meta_start);
+            add_range(meta_end, end);
+        } else {
+            add_range(start, end);
+        }
+    }
+    println!("Physical Frames: {} usable, {} for allocator", fa.total(), meta_frames);
+    drop(fa);
+
+    unsafe {
+        use x86_64::registers::control::Cr3;
+        KERNEL_L4 = Some(Cr3::read().0);
     }
+    init_kernel_space();
+}
+
+/// 为内核空间的每个L4 Entry预先分配L3页表
+///
+/// AddressSpace创建时复制内核空间的L4 Entry，之后新增的内核映射只会修改共享的L3及以下的页表，
+/// 所以对所有AddressSpace都可见。
+fn init_kernel_space() {
+    let l4 = unsafe { &mut *(phys_to_virt(kernel_l4().start_address()).as_mut_ptr::<PageTable>()) };
+    for entry in l4.iter_mut().skip(KERNEL_L4_START).filter(|e| e.is_unused()) {
+        let frame = GlobalFrameAllocator.allocate_frame().expect("no frame for kernel page table");
+        unsafe {
+            (*phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()).zero();
+        }
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
+
+/// 内核的L4页表
+pub fn kernel_l4() -> PhysFrame {
+    unsafe { KERNEL_L4.expect("memory not initialized") }
+}
+
+/// 为AP启动代码保留的Frame（1MiB以下），没有可用的低端内存时返回None
+pub fn trampoline_frame() -> Option<PhysFrame> {
+    unsafe { TRAMPOLINE_FRAME }
+}
+
+/// 物理地址转换成虚拟地址
+///
+/// bootloader已将全部物理内存映射到PHYS_MEM_OFS处，所以直接加上偏移即可。
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    unsafe { VirtAddr::new(PHYS_MEM_OFS + phys.as_u64()) }
+}
+
+/// 物理内存映射中的虚拟地址转换成物理地址（phys_to_virt的逆运算）
+pub fn virt_to_phys(virt: VirtAddr) -> PhysAddr {
+    unsafe { PhysAddr::new(virt.as_u64() - PHYS_MEM_OFS) }
 }
 
 /// 页表映射实现
@@ -67,58 +176,273 @@ impl PageTableImpl {
     pub fn active() -> Self {
         use x86_64::registers::control::Cr3;
         let l4_frame: PhysFrame = Cr3::read().0; // CR3保存L4的物理Frame
+        unsafe { Self::new(l4_frame) }
+    }
+
+    /// 获取指定L4页表的映射实例
+    ///
+    /// 调用者需要保证l4_frame是一个有效的L4页表，且同一时刻只有一个实例修改该页表。
+    pub unsafe fn new(l4_frame: PhysFrame) -> Self {
         let phys = l4_frame.start_address(); // l4_frame的物理地址
-        unsafe {
-            let phys_mem_ofs = VirtAddr::new(PHYS_MEM_OFS);
-            let virt = phys_mem_ofs + phys.as_u64(); // 访问L4的虚拟地址
-            let l4_page: *mut PageTable = virt.as_mut_ptr(); // L4的虚拟Page
-            PageTableImpl{
-                mapper: OffsetPageTable::new(&mut *l4_page, phys_mem_ofs),
-            }
+        let phys_mem_ofs = VirtAddr::new(PHYS_MEM_OFS);
+        let virt = phys_mem_ofs + phys.as_u64(); // 访问L4的虚拟地址
+        let l4_page: *mut PageTable = virt.as_mut_ptr(); // L4的虚拟Page
+        PageTableImpl{
+            mapper: OffsetPageTable::new(&mut *l4_page, phys_mem_ofs),
         }
     }
 
     /// 实现page到frame的映射
     pub fn map(&mut self, page: Page::<Size4KiB>, frame: PhysFrame::<Size4KiB>) {
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        let mut frame_allocator = GlobalFrameAllocator;
-        let map_to_result = unsafe {
-            self.mapper.map_to(page, frame, flags, &mut frame_allocator)
+        self.map_with_flags(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+
+    /// 使用指定的flags实现page到frame的映射
+    ///
+    /// 新建的中间页表会继承flags中的PRESENT、WRITABLE和USER_ACCESSIBLE。
+    pub fn map_with_flags(&mut self, page: Page::<Size4KiB>, frame: PhysFrame::<Size4KiB>, flags: PageTableFlags) {
+        self.try_map(page, frame, flags).expect("PageTableImpl::map failed");
+    }
+
+    /// 实现2MiB的page到frame的映射
+    pub fn map_2m(&mut self, page: Page::<Size2MiB>, frame: PhysFrame::<Size2MiB>, flags: PageTableFlags) {
+        self.try_map(page, frame, flags).expect("PageTableImpl::map_2m failed");
+    }
+
+    /// 实现1GiB的page到frame的映射（需要CPU支持，见supports_1g）
+    pub fn map_1g(&mut self, page: Page::<Size1GiB>, frame: PhysFrame::<Size1GiB>, flags: PageTableFlags) {
+        assert!(supports_1g(), "1GiB pages not supported");
+        self.try_map(page, frame, flags).expect("PageTableImpl::map_1g failed");
+    }
+
+    /// 实现page到frame的映射，失败时返回错误（如中间页表的Frame不足、page已经映射）
+    ///
+    /// 不能panic的调用者（如堆分配器、Page Fault处理）使用该方法。
+    pub fn try_map<S: PageSize>(&mut self, page: Page<S>, frame: PhysFrame<S>, flags: PageTableFlags) -> Result<(), MapToError<S>>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        with_page_table_lock(|| {
+            let flush = unsafe { self.mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)? };
+            flush.flush();
+            Ok(())
+        })
+    }
+
+    /// 虚拟地址对应的物理地址、flags和page的大小（支持huge page）
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags, u64)> {
+        use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+        match self.mapper.translate(addr) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                let (start, size) = match frame {
+                    MappedFrame::Size4KiB(f) => (f.start_address(), Size4KiB::SIZE),
+                    MappedFrame::Size2MiB(f) => (f.start_address(), Size2MiB::SIZE),
+                    MappedFrame::Size1GiB(f) => (f.start_address(), Size1GiB::SIZE),
+                };
+                Some((start + offset, flags, size))
+            },
+            _ => None,
+        }
+    }
+
+    /// 取消包含addr的page的映射（支持huge page），返回page对应的Frame的起始地址和大小
+    ///
+    /// 不会释放Frame，由调用者决定是否释放（返回时所有CPU的TLB都已经失效，可以立即释放）。
+    pub fn unmap(&mut self, addr: VirtAddr) -> Option<(PhysAddr, u64)> {
+        let result = self.unmap_local(addr)?;
+        flush_tlb(addr);
+        Some(result)
+    }
+
+    /// 与unmap相同，但只刷新当前CPU的TLB
+    ///
+    /// 只能用于其它CPU不可能访问过的page（如刚刚映射、还没有使用的page），
+    /// 或持有其它CPU会在关中断时等待的锁、不能等待TLB shootdown的情况（见ipi模块）。
+    pub fn unmap_local(&mut self, addr: VirtAddr) -> Option<(PhysAddr, u64)> {
+        with_page_table_lock(|| self.unmap_locked(addr))
+    }
+
+    fn unmap_locked(&mut self, addr: VirtAddr) -> Option<(PhysAddr, u64)> {
+        let (_, _, size) = self.translate(addr)?;
+        let start = match size {
+            Size4KiB::SIZE => self.mapper.unmap(Page::<Size4KiB>::containing_address(addr))
+                .map(|(frame, flush)| { flush.flush(); frame.start_address() }),
+            Size2MiB::SIZE => self.mapper.unmap(Page::<Size2MiB>::containing_address(addr))
+                .map(|(frame, flush)| { flush.flush(); frame.start_address() }),
+            _ => self.mapper.unmap(Page::<Size1GiB>::containing_address(addr))
+                .map(|(frame, flush)| { flush.flush(); frame.start_address() }),
         };
-        map_to_result.expect("PageTableImpl::map failed").flush();
+        start.ok().map(|start| (start, size))
     }
+
+    /// 取消[start, start + len)中已经映射的page（支持huge page），未映射的部分直接跳过
+    ///
+    /// 全部取消映射后只进行一次TLB shootdown；返回每个page对应的Frame的起始地址、flags和大小，
+    /// 返回时所有CPU的TLB都已经失效，可以立即释放Frame。
+    pub fn unmap_range(&mut self, start: VirtAddr, len: u64) -> Vec<(PhysAddr, PageTableFlags, u64)> {
+        let end = start + len;
+        let mut unmapped = Vec::new();
+        let mut addr = start.align_down(Size4KiB::SIZE);
+        while addr < end {
+            let flags = self.translate(addr).map(|(_, flags, _)| flags);
+            match flags.and_then(|flags| self.unmap_local(addr).map(|r| (flags, r))) {
+                Some((flags, (phys, size))) => {
+                    unmapped.push((phys, flags, size));
+                    addr = addr.align_down(size) + size;
+                },
+                None => addr += Size4KiB::SIZE,
+            }
+        }
+        if !unmapped.is_empty() {
+            flush_tlb_range(start, len);
+        }
+        unmapped
+    }
+
+    /// 修改page的flags，返回时所有CPU的TLB中的旧映射都已经失效；page未映射时返回false
+    pub fn update_flags(&mut self, page: Page::<Size4KiB>, flags: PageTableFlags) -> bool {
+        let updated = self.update_flags_local(page, flags);
+        if updated {
+            flush_tlb(page.start_address());
+        }
+        updated
+    }
+
+    /// 与update_flags相同，但只刷新当前CPU的TLB，由调用者在修改完一段范围后调用flush_tlb_range
+    pub fn update_flags_local(&mut self, page: Page::<Size4KiB>, flags: PageTableFlags) -> bool {
+        with_page_table_lock(|| match unsafe { self.mapper.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(_) => false,
+        })
+    }
+}
+
+/// 超过该page数时，flush_tlb_range直接刷新整个TLB
+const FLUSH_ALL_PAGES: u64 = 64;
+
+/// 使所有CPU的TLB中包含addr的page的映射失效（TLB shootdown）
+///
+/// 取消映射或修改flags（权限变小）之后调用；只有一个CPU时直接刷新当前CPU的TLB，
+/// 否则通过cross-call在每个CPU上刷新（见ipi模块），需要在关中断时等待的锁之外调用。
+pub fn flush_tlb(addr: VirtAddr) {
+    flush_tlb_range(addr, 1);
+}
+
+/// 使所有CPU的TLB中[start, start + len)的映射失效，整个范围只进行一次cross-call
+///
+/// page较多时直接刷新整个TLB（内核的映射不使用GLOBAL，重新加载CR3即可全部失效）。
+pub fn flush_tlb_range(start: VirtAddr, len: u64) {
+    use x86_64::instructions::tlb;
+    let first = start.align_down(Size4KiB::SIZE);
+    let pages = ((start + len.max(1)).align_up(Size4KiB::SIZE) - first) / Size4KiB::SIZE;
+    let flush = || {
+        if pages > FLUSH_ALL_PAGES {
+            tlb::flush_all();
+        } else {
+            for k in 0 .. pages {
+                tlb::flush(first + k * Size4KiB::SIZE);
+            }
+        }
+    };
+    if super::cpu::count() <= 1 {
+        flush();
+    } else {
+        super::ipi::call(super::ipi::IpiTarget::All, &flush);
+    }
+}
+
+/// CPU是否支持1GiB的page
+pub fn supports_1g() -> bool {
+    // CPUID.80000001H:EDX[26]
+    let ext = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) };
+    ext.eax >= 0x8000_0001 && unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & (1 << 26) != 0
 }
 
 /// Frame分配器
 ///
-/// 这是一个简单的Frame分配器，只支持4KiB大小的Frame。
+/// 支持4KiB和2MiB大小的Frame，以及2的幂个物理连续的Frame（allocate_contiguous）。
 #[derive(Debug, Clone, Copy)]
 pub struct GlobalFrameAllocator;
 
-unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
-    /// 申请一个4KiB的物理Frame
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+/// 物理Frame的统计信息
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// 可用的Frame总数
+    pub total: usize,
+    /// 空闲的Frame数
+    pub free: usize,
+    /// 已分配的Frame数
+    pub used: usize,
+    /// 每个order的空闲块数
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
+
+impl GlobalFrameAllocator {
+    /// 申请2^order个物理连续的4KiB Frame，返回第一个Frame
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
         interrupts::without_interrupts(|| {
-            if let Some(n) = FRAME_ALLOCATOR.lock().alloc() {
-                // FRAME_ALLOCATOR返回的地址再乘上Size4KiB一定是4KiB对齐的，故无需check
-                unsafe {
-                    Some(PhysFrame::from_start_address_unchecked(PhysAddr::new(n as u64 * Size4KiB::SIZE)))
-                }
-            } else {
-                None
+            FRAME_ALLOCATOR.lock().alloc(order).map(|n| {
+                // Buddy返回的Frame号再乘上Size4KiB一定是4KiB对齐的，故无需check
+                unsafe { PhysFrame::from_start_address_unchecked(PhysAddr::new(n as u64 * Size4KiB::SIZE)) }
+            })
+        })
+    }
+
+    /// 释放由allocate_contiguous(order)申请的Frame
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, order: usize) {
+        interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR
+                .lock()
+                .dealloc((frame.start_address().as_u64() / Size4KiB::SIZE) as usize, order);
+        })
+    }
+
+    /// 物理Frame的统计信息
+    pub fn stats() -> FrameStats {
+        interrupts::without_interrupts(|| {
+            let fa = FRAME_ALLOCATOR.lock();
+            FrameStats {
+                total: fa.total(),
+                free: fa.free(),
+                used: fa.total() - fa.free(),
+                free_blocks: fa.free_blocks(),
             }
         })
     }
 }
 
+/// 2MiB的Frame对应的order
+const ORDER_2MIB: usize = 9;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    /// 申请一个4KiB的物理Frame
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(0)
+    }
+}
+
 impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
     /// 释放一个4KiB的物理Frame
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
-        interrupts::without_interrupts(|| {
-            FRAME_ALLOCATOR
-                .lock()
-                .dealloc(frame.start_address().as_u64() as usize / Size4KiB::SIZE as usize);
-        })
+        self.deallocate_contiguous(frame, 0);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    /// 申请一个2MiB的物理Frame（Buddy分配的块按大小对齐）
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_contiguous(ORDER_2MIB)
+            .map(|f| PhysFrame::from_start_address(f.start_address()).unwrap())
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    /// 释放一个2MiB的物理Frame
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_contiguous(PhysFrame::containing_address(frame.start_address()), ORDER_2MIB);
     }
 }
 
@@ -168,10 +492,11 @@ unsafe fn print_page_table(pmo: VirtAddr) {
 /// 虚拟地址到物理地址的计算
 #[allow(dead_code)]
 fn print_virt2phys_translation(pmo: VirtAddr) {
+    let stack_value = 0u64;
     let addresses = [
         0xb8000, // VGA地址
         0x201008, // code页
-        0x0100_0020_1a10, // stack页
+        &stack_value as *const u64 as u64, // stack页
     ];
 
     for &addr in &addresses {
@@ -180,19 +505,22 @@ fn print_virt2phys_translation(pmo: VirtAddr) {
         use x86_64::registers::control::Cr3;
         let mut frame: PhysFrame = Cr3::read().0; // L4
 
-        for &idx in &[virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()] {
+        // 每一级页表中page内的偏移：L3中的huge page为1GiB，L2中的huge page为2MiB
+        let offsets = [0, virt.as_u64() & (Size1GiB::SIZE - 1), virt.as_u64() & (Size2MiB::SIZE - 1), 0];
+        let mut offset = u64::from(virt.page_offset());
+        for (level, &idx) in [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()].iter().enumerate() {
             let frame_virt = pmo + frame.start_address().as_u64();
             let table = unsafe { &*(frame_virt.as_ptr() as *const PageTable) };
             let entry = &table[idx];
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // frame()方法不支持huge page，直接使用entry中的地址
+                frame = PhysFrame::containing_address(entry.addr());
+                offset = offsets[level];
+                break;
+            }
             frame = entry.frame().unwrap(); // L4 -> L3 -> L2 -> L1
-            //use x86_64::structures::paging::page_table::FrameError;
-            //frame = match entry.frame() {
-            //    Ok(frame) => frame,
-            //    Err(FrameError::FrameNotPresent) => None,
-            //    Err(FrameError::HugeFrame) => panic!("huge pages not supported"), // frame()方法不支持huge page
-            //};
         }
-        let phys = frame.start_address() + u64::from(virt.page_offset());
+        let phys = frame.start_address() + offset;
 
         println!("{:?} -> {:?}", virt, phys);
     }
@@ -209,13 +537,12 @@ fn print_virt2phys_translation(pmo: VirtAddr) {
 #[allow(dead_code)]
 fn print_create_mapping() {
     use super::driver::vga::{BUF_ADDR, BUF_ROW, BUF_COL};
-    // 将page映射到frame
-    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0)); // 4KiB的page，包含地址0（会将地址对行4K对齐）
+    // 将vmalloc区域中的page映射到frame
     let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(BUF_ADDR)); // 4Kib的frame，包含vga地址（会将地址对行4K对齐）
-    let mut pg = PageTableImpl::active();
-    pg.map(page, frame);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let virt = super::vmalloc::vmap(&[frame], flags).expect("vmap failed");
 
-    let vga_ptr: *mut u16 = page.start_address().as_mut_ptr();
+    let vga_ptr: *mut u16 = (virt + (BUF_ADDR & 0xfff)).as_mut_ptr();
     unsafe {
         (vga_ptr as *mut u64).offset((BUF_COL * 4) as isize).write_volatile(0x_b021_f077_f065_f04e); // New!
         vga_ptr.offset((BUF_COL * BUF_ROW / 2 - 2) as isize).write_volatile(0x_f072); // r
@@ -236,14 +563,101 @@ fn print_create_mapping() {
         assert_eq!('q', vga.read_byte(BUF_ROW / 2, BUF_COL / 2 + 1));
         assert_eq!('r', vga.read_byte(BUF_ROW / 2, BUF_COL / 2 + 2));
     });
+    super::vmalloc::vfree(virt).expect("vfree failed");
 }
 
-/// 测试物理Frame有效性
+/// 打印物理Frame的统计信息
 #[allow(dead_code)]
 fn print_check_frame() {
-    if let Some(fa) = FRAME_ALLOCATOR.try_lock() {
-        for &addr in &[0x430, 0x780, 0x7fdf, 0x7fe0] {
-            println!("0x{:x} : {}", addr, fa.test(addr));
+    println!("{:?}", GlobalFrameAllocator::stats());
+}
+
+/// 2MiB page的映射、转换和取消映射
+#[test_case]
+fn test_map_huge_page() {
+    let start = super::vmalloc::reserve(Size2MiB::SIZE as usize, Size2MiB::SIZE).expect("vmalloc reserve failed");
+    let addr = start.as_u64();
+    let mut pg = PageTableImpl::active();
+    let frame: PhysFrame<Size2MiB> = GlobalFrameAllocator.allocate_frame().expect("no 2MiB frame");
+    let page = Page::<Size2MiB>::containing_address(start);
+    pg.map_2m(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+
+    let virt = VirtAddr::new(addr + 0x12_3458);
+    let (phys, flags, size) = pg.translate(virt).unwrap();
+    assert_eq!(phys, frame.start_address() + 0x12_3458u64);
+    assert!(flags.contains(PageTableFlags::HUGE_PAGE));
+    assert_eq!(size, Size2MiB::SIZE);
+    unsafe {
+        virt.as_mut_ptr::<u64>().write_volatile(0x5a5a);
+        assert_eq!(phys_to_virt(phys).as_ptr::<u64>().read_volatile(), 0x5a5a);
+    }
+
+    assert_eq!(pg.unmap(virt), Some((frame.start_address(), Size2MiB::SIZE)));
+    assert!(pg.translate(virt).is_none());
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    super::vmalloc::release(start).expect("vmalloc release failed");
+}
+
+/// unmap_range跳过未映射的page，返回被取消映射的Frame
+#[test_case]
+fn test_unmap_range() {
+    let start = super::vmalloc::reserve(4 * Size4KiB::SIZE as usize, Size4KiB::SIZE).expect("vmalloc reserve failed");
+    let addr = start.as_u64();
+    let mut pg = PageTableImpl::active();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut frames = [None; 4];
+    for k in [0, 1, 3] {
+        let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().unwrap();
+        pg.map_with_flags(Page::containing_address(VirtAddr::new(addr + k * Size4KiB::SIZE)), frame, flags);
+        frames[k as usize] = Some(frame.start_address());
+    }
+
+    let unmapped = pg.unmap_range(start, 4 * Size4KiB::SIZE);
+    let phys: Vec<_> = unmapped.iter().map(|&(phys, _, size)| { assert_eq!(size, Size4KiB::SIZE); Some(phys) }).collect();
+    assert_eq!(phys, [frames[0], frames[1], frames[3]]);
+    assert!(unmapped.iter().all(|&(_, f, _)| f.contains(flags)));
+    for k in 0 .. 4 {
+        assert!(pg.translate(VirtAddr::new(addr + k * Size4KiB::SIZE)).is_none());
+    }
+    for (phys, _, _) in unmapped {
+        unsafe { GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)) };
+    }
+    super::vmalloc::release(start).expect("vmalloc release failed");
+}
+
+/// 连续Frame和2MiB Frame的分配与回收
+#[test_case]
+fn test_frame_allocator() {
+    // 其它CPU可能同时分配Frame，所以只检查本测试分配的Frame，不检查全局的统计信息
+    let mut fa = GlobalFrameAllocator;
+    let run = fa.allocate_contiguous(4).expect("no contiguous frames");
+    let run_size = Size4KiB::SIZE << 4;
+    assert_eq!(run.start_address().as_u64() % run_size, 0);
+    let huge: PhysFrame<Size2MiB> = fa.allocate_frame().expect("no 2MiB frame");
+    assert!(huge.start_address().is_aligned(Size2MiB::SIZE));
+    let small: PhysFrame<Size4KiB> = fa.allocate_frame().unwrap();
+
+    // 分配的范围互不重叠
+    let ranges = [
+        (run.start_address().as_u64(), run_size),
+        (huge.start_address().as_u64(), Size2MiB::SIZE),
+        (small.start_address().as_u64(), Size4KiB::SIZE),
+    ];
+    for (k, &(a, a_size)) in ranges.iter().enumerate() {
+        for &(b, b_size) in &ranges[k + 1 ..] {
+            assert!(a + a_size <= b || b + b_size <= a, "{:#x} overlaps {:#x}", a, b);
         }
     }
+    // 连续的Frame可以作为一整块内存访问
+    unsafe {
+        let ptr = phys_to_virt(run.start_address()).as_mut_ptr::<u8>();
+        core::ptr::write_bytes(ptr, 0xa5, run_size as usize);
+        assert_eq!(ptr.add(run_size as usize - 1).read_volatile(), 0xa5);
+    }
+
+    unsafe {
+        fa.deallocate_frame(small);
+        fa.deallocate_frame(huge);
+        fa.deallocate_contiguous(run, 4);
+    }
 }
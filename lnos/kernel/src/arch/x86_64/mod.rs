@@ -6,13 +6,33 @@
 use bootloader::BootInfo;
 
 pub mod panic;
+pub mod backtrace;
 pub mod driver;
 pub mod io;
 pub mod gdt;
+pub mod cpu;
 pub mod idt;
+pub mod trap;
 pub mod pic;
+pub mod pit;
+pub mod hpet;
+pub mod tsc;
+pub mod irq;
+pub mod apic;
+pub mod ipi;
+pub mod buddy;
 pub mod memory;
+pub mod vmalloc;
+pub mod ioremap;
+pub mod kstack;
+pub mod addr_space;
+pub mod vma;
+pub mod slab;
 pub mod allocator;
+pub mod context;
+pub mod syscall;
+pub mod usermode;
+pub mod smp;
 
 
 /// Kernel入口函数
@@ -25,10 +45,19 @@ pub fn kernel_start(boot_info: &'static BootInfo) -> ! {
     println!("Hello lnos!");
 
     memory::init(&boot_info);
+    cpu::init();
+    idt::init(); // 堆按需映射，需要先设置Page Fault的处理函数
     allocator::init().expect("failed to init allocator");
-    gdt::init();
-    idt::init();
+    ipi::init();
+    ioremap::init();
+    driver::acpi::init();
+    syscall::init();
     pic::init();
+    hpet::init();
+    tsc::init();
+    driver::rtc::init();
+    crate::kthread::init();
+    smp::init();
 
     x86_64::instructions::interrupts::enable(); // 使能中断
 
@@ -44,10 +73,19 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     println!("Running liblnos test");
 
     memory::init(&boot_info);
+    cpu::init();
+    idt::init(); // 堆按需映射，需要先设置Page Fault的处理函数
     allocator::init().expect("failed to init allocator");
-    gdt::init();
-    idt::init();
+    ipi::init();
+    ioremap::init();
+    driver::acpi::init();
+    syscall::init();
     pic::init();
+    hpet::init();
+    tsc::init();
+    driver::rtc::init();
+    crate::kthread::init();
+    smp::init();
 
     crate::test_main();
 
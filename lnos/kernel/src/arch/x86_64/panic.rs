@@ -2,14 +2,39 @@
 //!
 //! Rust在发生panic时，会调用panic_handler；
 //! 对于kernel同样需要设置handler（并且对于build和test均需要设置）。
+//!
+//! panic时会打印调用栈（见backtrace模块）；若打印调用栈时再次panic，则不再打印。
+//!
+//! cotask的task在开中断时panic，只终止该task：打印后返回到Executor（见cotask::executor::begin_task_panic），
+//! JoinHandle得到JoinError::Panicked，其它task和线程继续执行。
+//! 跳过的栈帧不会drop，task的Future被泄露，task自己持有的锁也不会释放；
+//! 关中断时（可能持有内核的锁，或在中断处理中）panic仍然是kernel panic。
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+
+/// 是否已经在处理panic
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 
 /// Kernel painc处理函数
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    let enabled = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::disable();
+    if let Some(task) = crate::cotask::executor::begin_task_panic(enabled) {
+        println!("Panic in task {:?}: {}\n", task, info);
+        if !PANICKING.swap(true, Ordering::SeqCst) {
+            super::backtrace::print();
+            PANICKING.store(false, Ordering::SeqCst);
+        }
+        crate::cotask::executor::end_task_panic();
+    }
     println!("Panic: {}\n", info);
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        super::backtrace::print();
+    }
 
     #[cfg(test)]
     {
@@ -1,12 +1,15 @@
 //! PIC模块
 //!
-//! 使用经共8259作为PIC；
+//! 优先使用APIC（Local APIC + IO-APIC）作为中断控制器；
+//! 若不支持APIC，则使用经典8259作为PIC。
 //!
-//! TODO 使用APIC替代8259
+//! 中断号沿用8259的设置（即PicIRQ），使用APIC时通过IO-APIC将ISA中断重定向到相同的中断号。
+//! 初始化后所有IRQ均被屏蔽，由irq模块在注册处理函数时使能（unmask）。
 
 use spin;
+use core::sync::atomic::{AtomicBool, Ordering};
 use pic8259::ChainedPics;
-use x86_64::structures::idt::InterruptStackFrame;
+use super::{apic, irq, pit};
 
 
 /// Primary PIC起始中断号
@@ -31,25 +34,100 @@ pub enum PicIRQ {
 impl PicIRQ {
     pub fn as_u8(self) -> u8 { self as u8 }
     pub fn as_usize(self) -> usize { usize::from(self.as_u8()) }
+    /// 对应的ISA中断号（IRQ0~15）
+    pub fn as_isa(self) -> u8 { self.as_u8() - PIC_1_OFFSET }
 }
 
+/// 是否使用APIC作为中断控制器
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
 
+/// 8259中连接Secondary PIC的IRQ
+const CASCADE_IRQ: u8 = 2;
+
+
+/// 初始化中断控制器
+///
+/// 需要在acpi::init之后调用。
 pub fn init() {
+    // 无论是否使用APIC，都需要先初始化8259，将其中断号重映射到32~47，
+    // 防止8259产生的伪中断与CPU Exception的中断号冲突。
     unsafe { PICS.lock().initialize(); };
+
+    if apic::init() {
+        unsafe { PICS.lock().disable(); } // 屏蔽8259的所有中断
+        APIC_ENABLED.store(true, Ordering::SeqCst);
+        println!("Interrupt Controller: APIC");
+    } else {
+        // 只保留Secondary PIC的级联
+        unsafe { PICS.lock().write_masks(!(1 << CASCADE_IRQ), 0xff); }
+        println!("Interrupt Controller: 8259 PIC");
+    }
+
+    // PIT通道0连接IRQ0，按time::HZ的频率触发Timer中断
+    pit::init(crate::time::HZ as u32);
+    irq::register_irq(PicIRQ::Timer.as_isa(), timer_handler).expect("failed to register timer");
+    irq::register_irq(PicIRQ::Keyboard.as_isa(), keyboard_handler).expect("failed to register keyboard");
 }
 
+/// 是否使用APIC作为中断控制器
+pub fn apic_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::Relaxed)
+}
 
-/// Timer中断(No = 32)
-pub extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/// 通知中断控制器，已经完成ISA中断irq的处理，不然无法响应下一个中断
+pub fn end_of_interrupt(irq: u8) {
+    if apic_enabled() {
+        apic::eoi();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(irq::vector(irq));
+        }
+    }
+}
+
+/// 使能ISA中断irq
+pub fn unmask(irq: u8) {
+    if apic_enabled() {
+        apic::route_isa_irq(irq, irq::vector(irq));
+    } else {
+        set_pic_mask(irq, false);
+    }
+}
+
+/// 屏蔽ISA中断irq
+pub fn mask(irq: u8) {
+    if apic_enabled() {
+        apic::mask_isa_irq(irq);
+    } else {
+        set_pic_mask(irq, true);
+    }
+}
+
+fn set_pic_mask(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
     unsafe {
-        // 通知PIC，已经完成中断处理，不然无法响应下一个中断
-        PICS.lock().notify_end_of_interrupt(PicIRQ::Timer.as_u8());
+        let mut masks = pics.read_masks();
+        let (index, bit) = ((irq / 8) as usize, 1 << (irq % 8));
+        if masked {
+            masks[index] |= bit;
+        } else {
+            masks[index] &= !bit;
+        }
+        pics.write_masks(masks[0], masks[1]);
     }
 }
 
+
+/// Timer中断(No = 32)
+///
+/// 更新系统时钟（唤醒到期的定时器），并驱动内核线程的调度：irq模块在通知EOI后检查是否需要抢占当前线程。
+fn timer_handler(_irq: u8) {
+    crate::time::tick();
+    crate::kthread::tick();
+}
+
 /// Keyboard中断(No = 33)
-pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_handler(_irq: u8) {
     /* 直接在中断中读取按键码，并处理按键
     use spin::Mutex;
     use lazy_static::lazy_static;
@@ -85,9 +163,4 @@ pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame
     let mut port = Port::new(0x60); // 通过端口0x60读取PS/2 controller的数据
     let scancode: u8 = unsafe { port.read() }; // 读取按键scancode
     crate::driver::keyboard::append_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(PicIRQ::Keyboard.as_u8());
-    }
 }
@@ -0,0 +1,103 @@
+//! PIT模块
+//!
+//! 8253/8254 PIT（Programmable Interval Timer）的输入时钟为PIT_FREQUENCY，
+//! 通道0的输出连接到IRQ0，设置分频值后即以指定的频率触发Timer中断；
+//! 通道2的Gate由端口0x61控制，不产生中断，用于校准其它时钟源（如TSC）的频率。
+
+use x86_64::instructions::port::Port;
+use crate::time::{self, ClockSource};
+
+
+/// PIT的输入时钟频率（Hz）
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// 通道0的数据端口
+const CHANNEL0: u16 = 0x40;
+/// 通道2的数据端口
+const CHANNEL2: u16 = 0x42;
+/// 模式/命令端口
+const COMMAND: u16 = 0x43;
+/// 控制通道2的Gate（bit0）和扬声器（bit1），bit5为通道2的输出
+const GATE_PORT: u16 = 0x61;
+
+/// 使用Timer中断计数的时钟源（精度为1/HZ秒），在没有其它时钟源时使用
+pub struct PitClock;
+
+impl ClockSource for PitClock {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn read(&self) -> u64 {
+        time::ticks()
+    }
+
+    fn frequency(&self) -> u64 {
+        time::HZ
+    }
+
+    fn rating(&self) -> u32 {
+        10
+    }
+}
+
+/// 设置通道0以hz的频率触发中断，并注册PitClock，返回实际的频率
+///
+/// 命令0x34：通道0，先写低字节再写高字节，模式2（Rate Generator），二进制计数。
+pub fn init(hz: u32) -> u32 {
+    // 分频值为16位，0表示65536
+    let divisor = ((PIT_FREQUENCY + hz / 2) / hz).clamp(1, 0x10000);
+    unsafe {
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL0);
+        command.write(0x34);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    time::register_clock_source(&PitClock);
+    PIT_FREQUENCY / divisor
+}
+
+/// 停止通道0的周期中断（由其它定时器代替PIT产生Timer中断时）
+///
+/// 设置为模式0（计数结束后输出保持高电平），只会再产生一次中断。
+pub fn stop() {
+    unsafe {
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL0);
+        command.write(0x30);
+        data.write(0);
+        data.write(0);
+    }
+}
+
+/// 使用通道2校准计数器的频率
+///
+/// 通道2以模式0（计数结束时输出变为高电平）计时ms毫秒，返回read的计数值在这段时间内的增量换算成的频率。
+/// 不需要中断，但计时期间不能被打断，需要在关中断时调用。
+pub fn calibrate(ms: u32, mut read: impl FnMut() -> u64) -> u64 {
+    let count = (PIT_FREQUENCY as u64 * ms as u64 / 1000).min(0xffff) as u16;
+    unsafe {
+        let mut gate = Port::<u8>::new(GATE_PORT);
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL2);
+
+        // 关闭扬声器，暂停通道2
+        let val = gate.read() & !0x03;
+        gate.write(val);
+        // 命令0xb0：通道2，先写低字节再写高字节，模式0，二进制计数
+        command.write(0xb0);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // 打开Gate开始计数，等待输出变为高电平
+        gate.write(val | 0x01);
+        let start = read();
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = read();
+        gate.write(val);
+        (end - start) * PIT_FREQUENCY as u64 / count as u64
+    }
+}
@@ -0,0 +1,232 @@
+//! Slab分配器模块
+//!
+//! 每个SlabCache管理一种大小的对象，每个slab是一个4KiB的物理Frame，
+//! 通过物理内存映射（phys_to_virt）访问，不占用堆的地址范围：
+//! - slab的开头保存SlabHeader，之后按对象大小切分，空闲对象组成链表；
+//! - 对象所在的slab为对象地址按4KiB向下对齐，所以回收时不需要查找；
+//! - partial为有空闲对象的slab链表，已满的slab不在任何链表中；
+//! - 完全空闲的slab保留一个（empty），避免反复申请释放Frame，其余的直接归还给GlobalFrameAllocator。
+//!
+//! slab的Frame计入堆的大小（见allocator::HeapStats::slab），受堆的上限限制：
+//! 由调用者决定是否可以申请新的slab（见SlabCache::alloc）。
+
+use core::{mem, ptr::null_mut};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+use super::memory::{phys_to_virt, virt_to_phys, GlobalFrameAllocator};
+
+
+const SLAB_SIZE: usize = Size4KiB::SIZE as usize;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// 保存在slab开头的管理数据
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free_list: *mut FreeObject,
+    /// 空闲对象数
+    free: usize,
+    /// 对象总数
+    total: usize,
+}
+
+/// 一种大小的对象的Slab缓存
+pub struct SlabCache {
+    size: usize,
+    partial: *mut SlabHeader,
+    empty: *mut SlabHeader,
+    allocated: usize,
+    free: usize,
+    slabs: usize,
+}
+
+/// SlabCache只在GlobalHeapAllocator的锁中访问
+unsafe impl Send for SlabCache {}
+
+/// SlabCache的统计信息
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    /// 对象大小
+    pub size: usize,
+    /// 已分配的对象数
+    pub allocated: usize,
+    /// 空闲的对象数
+    pub free: usize,
+    /// slab数
+    pub slabs: usize,
+}
+
+impl SlabCache {
+    /// 创建对象大小为size的SlabCache，size需要是2的幂，且不小于8
+    pub const fn new(size: usize) -> Self {
+        SlabCache {
+            size,
+            partial: null_mut(),
+            empty: null_mut(),
+            allocated: 0,
+            free: 0,
+            slabs: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            size: self.size,
+            allocated: self.allocated,
+            free: self.free,
+            slabs: self.slabs,
+        }
+    }
+
+    /// 分配一个对象，没有可用的Frame时返回null
+    ///
+    /// grow为false时不申请新的slab（如已经达到堆的上限），只使用已有的slab。
+    pub fn alloc(&mut self, grow: bool) -> *mut u8 {
+        unsafe {
+            if self.partial.is_null() {
+                let slab = if !self.empty.is_null() {
+                    mem::replace(&mut self.empty, null_mut())
+                } else {
+                    if !grow {
+                        return null_mut();
+                    }
+                    match self.new_slab() {
+                        Some(slab) => slab,
+                        None => return null_mut(),
+                    }
+                };
+                self.push_partial(slab);
+            }
+
+            let slab = self.partial;
+            let obj = (*slab).free_list;
+            (*slab).free_list = (*obj).next;
+            (*slab).free -= 1;
+            if (*slab).free == 0 {
+                self.unlink(slab);
+            }
+            self.allocated += 1;
+            self.free -= 1;
+            obj as *mut u8
+        }
+    }
+
+    /// 回收一个对象，ptr需要是由该SlabCache分配的
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let obj = ptr as *mut FreeObject;
+        (*obj).next = (*slab).free_list;
+        (*slab).free_list = obj;
+        if (*slab).free == 0 {
+            self.push_partial(slab);
+        }
+        (*slab).free += 1;
+        self.allocated -= 1;
+        self.free += 1;
+
+        if (*slab).free == (*slab).total {
+            self.unlink(slab);
+            if self.empty.is_null() {
+                self.empty = slab;
+            } else {
+                self.release(slab);
+            }
+        }
+    }
+
+    /// 申请一个Frame作为新的slab
+    unsafe fn new_slab(&mut self) -> Option<*mut SlabHeader> {
+        let frame: PhysFrame = GlobalFrameAllocator.allocate_frame()?;
+        let base = phys_to_virt(frame.start_address()).as_u64() as usize;
+        // 第一个对象按对象大小对齐
+        let first = (mem::size_of::<SlabHeader>() + self.size - 1) & !(self.size - 1);
+        let total = (SLAB_SIZE - first) / self.size;
+
+        let mut free_list = null_mut();
+        for k in (0 .. total).rev() {
+            let obj = (base + first + k * self.size) as *mut FreeObject;
+            (*obj).next = free_list;
+            free_list = obj;
+        }
+        let slab = base as *mut SlabHeader;
+        slab.write(SlabHeader {
+            prev: null_mut(),
+            next: null_mut(),
+            free_list,
+            free: total,
+            total,
+        });
+        self.slabs += 1;
+        self.free += total;
+        Some(slab)
+    }
+
+    /// 将slab归还给GlobalFrameAllocator
+    unsafe fn release(&mut self, slab: *mut SlabHeader) {
+        self.slabs -= 1;
+        self.free -= (*slab).total;
+        let phys = virt_to_phys(VirtAddr::new(slab as u64));
+        GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys));
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+
+
+#[test_case]
+fn test_slab_cache() {
+    use alloc::vec::Vec;
+
+    let mut cache = SlabCache::new(128);
+    let per_slab = (SLAB_SIZE - 128) / 128;
+    let objs: Vec<*mut u8> = (0 .. per_slab * 3).map(|_| cache.alloc(true)).collect();
+    assert!(objs.iter().all(|p| !p.is_null() && *p as usize % 128 == 0));
+    let stats = cache.stats();
+    assert_eq!((stats.allocated, stats.free, stats.slabs), (per_slab * 3, 0, 3));
+
+    // 释放后保留一个空的slab，其余的归还
+    for &p in objs.iter() {
+        unsafe { cache.dealloc(p) };
+    }
+    let stats = cache.stats();
+    assert_eq!((stats.allocated, stats.free, stats.slabs), (0, per_slab, 1));
+
+    // 空的slab被重新使用，不能申请新的slab时也可以分配
+    let p = cache.alloc(false);
+    assert!(!p.is_null());
+    assert_eq!(cache.stats().slabs, 1);
+    unsafe { cache.dealloc(p) };
+    let empty = mem::replace(&mut cache.empty, null_mut());
+    unsafe { cache.release(empty) };
+    assert_eq!(cache.stats().slabs, 0);
+}
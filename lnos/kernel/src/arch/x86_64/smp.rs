@@ -0,0 +1,334 @@
+//! SMP模块
+//!
+//! BSP（Bootstrap Processor）启动后，其它CPU Core（AP，Application Processor）处于等待状态，
+//! 由BSP通过Local APIC发送INIT-SIPI-SIPI启动：
+//! - AP收到Start-up IPI后，以实模式从物理地址vector * 0x1000处开始执行，所以启动代码（trampoline）
+//!   需要复制到1MiB以下的Frame（由memory模块保留），且该Frame需要恒等映射；
+//! - trampoline直接从实模式进入长模式（同时使能PE和PG），使用内核的L4页表，
+//!   然后切换到BSP为其分配的内核栈，调用ap_entry；
+//! - ap_entry初始化AP的Per-CPU数据、IDT、PAT、SYSCALL和Local APIC，然后作为该CPU的idle线程参与调度，
+//!   并在其中运行该CPU的cotask Executor。
+//!
+//! AP依次启动：BSP等待一个AP上线后再启动下一个，所有AP共用trampoline中的参数。
+//! AP超时没有进入ap_entry时，BSP重新发送INIT使其回到等待SIPI的状态（park），
+//! 所以之后启动的AP修改参数时，不会有迟到的AP使用新的参数（见ApState）。
+//! CPU列表来自ACPI的MADT，需要使用APIC作为中断控制器。
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
+use alloc::boxed::Box;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr4, Cr4Flags},
+    structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+use crate::time::{self, Instant};
+use super::cpu::{self, Cpu, MAX_CPUS};
+use super::driver::acpi;
+use super::kstack::KernelStack;
+use super::memory::{self, PageTableImpl};
+use super::{apic, idt, ioremap, ipi, pic, syscall};
+
+
+/// AP启动栈（即AP的idle线程的栈）大小
+const AP_STACK_SIZE: usize = 0x1000 * 4;
+
+/// 等待AP上线的超时时间
+const AP_TIMEOUT: Duration = Duration::from_millis(200);
+
+// trampoline：从实模式直接进入长模式（使用AT&T语法，便于使用符号的差值作为偏移）
+//
+// 实模式下CS = 所在Frame的物理地址 >> 4，IP = 0，所以先由CS算出trampoline的物理地址（ebx），
+// 再修正GDT的地址和跳转到64位代码的地址（trampoline可能被复制到任意Frame）。
+global_asm!(r#"
+.section .rodata
+.balign 16
+.global __ap_trampoline_start
+.global __ap_trampoline_params
+.global __ap_trampoline_end
+.code16
+__ap_trampoline_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    xorl %ebx, %ebx
+    movw %ax, %bx
+    shll $4, %ebx
+    leal (ap_gdt - __ap_trampoline_start)(%ebx), %eax
+    movl %eax, (ap_gdtr - __ap_trampoline_start + 2)
+    leal (ap_long_mode - __ap_trampoline_start)(%ebx), %eax
+    movl %eax, (ap_far_ptr - __ap_trampoline_start)
+    lgdtl (ap_gdtr - __ap_trampoline_start)
+    # CR4使用BSP的值（至少需要PAE）
+    movl (__ap_trampoline_params - __ap_trampoline_start + 8), %eax
+    orl $0x20, %eax
+    movl %eax, %cr4
+    movl (__ap_trampoline_params - __ap_trampoline_start), %eax
+    movl %eax, %cr3
+    # EFER：LME和NXE
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $0x900, %eax
+    wrmsr
+    # CR0：PG、WP、ET、PE（同时清除CD和NW，使能缓存）
+    movl $0x80010011, %eax
+    movl %eax, %cr0
+    ljmpl *(ap_far_ptr - __ap_trampoline_start)
+.code64
+ap_long_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    xorl %eax, %eax
+    movw %ax, %fs
+    movw %ax, %gs
+    # 从32位进入64位后，寄存器的高32位未定义
+    movl %ebx, %ebx
+    movq (__ap_trampoline_params - __ap_trampoline_start + 16)(%rbx), %rsp
+    movq (__ap_trampoline_params - __ap_trampoline_start + 32)(%rbx), %rdi
+    movq (__ap_trampoline_params - __ap_trampoline_start + 24)(%rbx), %rax
+    xorl %ebp, %ebp
+    callq *%rax
+    ud2
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00209a0000000000
+    .quad 0x0000920000000000
+ap_gdtr:
+    .word 23
+    .long 0
+ap_far_ptr:
+    .long 0
+    .word 0x08
+.balign 8
+__ap_trampoline_params:
+    .zero 40
+__ap_trampoline_end:
+.text
+"#, options(att_syntax));
+
+extern "C" {
+    static __ap_trampoline_start: u8;
+    static __ap_trampoline_params: u8;
+    static __ap_trampoline_end: u8;
+}
+
+/// trampoline的参数，与__ap_trampoline_params中的偏移对应
+#[repr(C)]
+struct ApParams {
+    cr3: u64,
+    cr4: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+/// 当前启动的AP的状态
+///
+/// BSP和AP通过compare_exchange竞争WAITING：AP先进入ap_entry则BSP一直等待其上线，
+/// BSP先超时则AP不再继续初始化（BSP同时发送INIT使其停止）。
+mod ap_state {
+    /// 已经发送INIT-SIPI，等待AP进入ap_entry
+    pub const WAITING: u8 = 0;
+    /// AP已经进入ap_entry，正在初始化
+    pub const STARTED: u8 = 1;
+    /// AP已经上线
+    pub const READY: u8 = 2;
+    /// AP超时，被BSP放弃
+    pub const ABANDONED: u8 = 3;
+}
+
+static AP_STATE: AtomicU8 = AtomicU8::new(ap_state::WAITING);
+
+/// 启动所有AP
+///
+/// 需要在kthread::init之后调用（AP上线后即参与线程调度），返回上线的CPU总数。
+pub fn init() -> usize {
+    let madt = match acpi::madt() {
+        Some(madt) if pic::apic_enabled() => madt,
+        _ => return cpu::count(),
+    };
+    let frame = match memory::trampoline_frame() {
+        Some(frame) => frame,
+        None => {
+            println!("SMP: no low memory for AP trampoline");
+            return cpu::count();
+        },
+    };
+
+    // 启动时关中断，需要不依赖Timer中断的时钟源（TSC或HPET）计时
+    if matches!(time::clock_source_name(), None | Some("pit")) {
+        println!("SMP: no high resolution clock source");
+        return cpu::count();
+    }
+
+    // 所有AP的Local APIC定时器频率相同，在BSP上校准
+    let freq = interrupts::without_interrupts(apic::calibrate_timer);
+    println!("SMP: Local APIC timer {} Hz", freq);
+
+    let identity = match map_trampoline(frame) {
+        Some(identity) => identity,
+        None => {
+            println!("SMP: trampoline frame {:?} is not identity mappable", frame);
+            return cpu::count();
+        },
+    };
+
+    let bsp = cpu::this_cpu().apic_id();
+    for lapic in madt.lapics.iter().filter(|l| l.usable() && l.apic_id as u32 != bsp) {
+        let id = cpu::count();
+        if id >= MAX_CPUS {
+            println!("SMP: too many CPUs, ignore APIC {}", lapic.apic_id);
+            continue;
+        }
+        if !start_ap(frame, id, lapic.apic_id as u32) {
+            println!("SMP: CPU with APIC {} did not respond", lapic.apic_id);
+        }
+    }
+
+    if identity {
+        PageTableImpl::active().unmap(VirtAddr::new(frame.start_address().as_u64()));
+    }
+    println!("SMP: {} CPUs online", cpu::count());
+    cpu::count()
+}
+
+/// 将trampoline复制到frame，并在内核页表中恒等映射
+///
+/// 返回Some(true)表示新建了映射（启动完成后需要取消），Some(false)表示已经存在恒等映射。
+fn map_trampoline(frame: PhysFrame) -> Option<bool> {
+    unsafe {
+        let start = &__ap_trampoline_start as *const u8;
+        let len = &__ap_trampoline_end as *const u8 as usize - start as usize;
+        assert!(len <= Size4KiB::SIZE as usize);
+        core::ptr::copy_nonoverlapping(start, memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), len);
+    }
+    let addr = VirtAddr::new(frame.start_address().as_u64());
+    let mut pg = unsafe { PageTableImpl::new(memory::kernel_l4()) };
+    match pg.translate(addr) {
+        Some((phys, _, _)) if phys == frame.start_address() => Some(false),
+        Some(_) => None,
+        None => {
+            pg.map_with_flags(Page::containing_address(addr), frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            Some(true)
+        },
+    }
+}
+
+/// 忙等待duration
+fn delay(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// 启动APIC ID为apic_id的AP，编号为id，返回是否上线
+fn start_ap(frame: PhysFrame, id: usize, apic_id: u32) -> bool {
+    let stack = match KernelStack::new(AP_STACK_SIZE, &alloc::format!("cpu{}", id)) {
+        Ok(stack) => stack,
+        Err(e) => {
+            println!("SMP: failed to allocate stack for CPU {}: {:?}", id, e);
+            return false;
+        },
+    };
+    // Cpu和启动栈在AP的整个生命周期中使用，不会释放
+    let cpu = Box::leak(Box::new(Cpu::new(id, apic_id)));
+    let params = ApParams {
+        cr3: memory::kernel_l4().start_address().as_u64(),
+        cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+        stack_top: stack.top().as_u64(),
+        entry: ap_entry as extern "C" fn(&'static mut Cpu) -> ! as u64,
+        cpu: cpu as *mut Cpu as u64,
+    };
+    unsafe {
+        let offset = &__ap_trampoline_params as *const u8 as usize - &__ap_trampoline_start as *const u8 as usize;
+        let dst = memory::phys_to_virt(frame.start_address() + offset).as_mut_ptr::<ApParams>();
+        core::ptr::write_volatile(dst, params);
+    }
+    AP_STATE.store(ap_state::WAITING, Ordering::SeqCst);
+
+    // Cpu和启动栈在AP的整个生命周期中使用；AP没有响应时同样不释放（无法确认INIT已经使其停止）
+    core::mem::forget(stack);
+
+    // INIT，等待10ms，然后发送两次SIPI（第一次可能被忽略）
+    let lapic = apic::local_apic();
+    let vector = (frame.start_address().as_u64() / Size4KiB::SIZE) as u8;
+    lapic.send_init(apic_id);
+    delay(Duration::from_millis(10));
+    for _ in 0 .. 2 {
+        lapic.send_startup(apic_id, vector);
+        let start = Instant::now();
+        while start.elapsed() < AP_TIMEOUT / 2 {
+            if AP_STATE.load(Ordering::SeqCst) == ap_state::READY {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    match AP_STATE.compare_exchange(ap_state::WAITING, ap_state::ABANDONED, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => {
+            // AP还没有进入ap_entry：重新INIT，使其回到等待SIPI的状态，不再使用trampoline中的参数
+            lapic.send_init(apic_id);
+            delay(Duration::from_millis(10));
+            false
+        },
+        Err(_) => {
+            // AP已经在初始化，等待其完成（之后的AP会覆盖trampoline中的参数）
+            while AP_STATE.load(Ordering::SeqCst) != ap_state::READY {
+                core::hint::spin_loop();
+            }
+            true
+        },
+    }
+}
+
+/// AP的Rust入口（由trampoline调用，已关中断）
+extern "C" fn ap_entry(cpu: &'static mut Cpu) -> ! {
+    if AP_STATE.compare_exchange(ap_state::WAITING, ap_state::STARTED, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        // BSP已经超时放弃，不能使用（可能已经属于其它AP的）Cpu和栈
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    unsafe { cpu::init_ap(cpu) };
+    idt::init();
+    ioremap::init();
+    syscall::init();
+    apic::init_ap();
+    crate::kthread::init_ap();
+
+    let cpu = cpu::this_cpu();
+    println!("CPU {} (APIC {}) online", cpu.id(), cpu.apic_id());
+    ipi::init();
+    AP_STATE.store(ap_state::READY, Ordering::SeqCst);
+
+    interrupts::enable();
+    crate::cotask::run_ap();
+}
+
+
+
+/// MADT中所有可用的CPU（最多MAX_CPUS个）都已经启动，APIC ID唯一，BSP的编号为0
+#[test_case]
+fn test_cpus() {
+    let expected = match acpi::madt() {
+        Some(madt) if pic::apic_enabled() => madt.lapics.iter().filter(|l| l.usable()).count().min(MAX_CPUS),
+        _ => 1,
+    };
+    assert_eq!(cpu::count(), expected);
+
+    let mut apic_ids = alloc::vec::Vec::new();
+    cpu::for_each_cpu(|cpu| {
+        assert!(!apic_ids.contains(&cpu.apic_id()));
+        apic_ids.push(cpu.apic_id());
+    });
+    assert_eq!(apic_ids.len(), cpu::count());
+    assert_eq!(cpu::get(0).map(Cpu::id), Some(0));
+}
@@ -0,0 +1,128 @@
+//! 系统调用模块
+//!
+//! 用户态使用SYSCALL指令进入内核：
+//! - CPU从LSTAR获取入口地址，将用户态的rip保存到rcx，rflags保存到r11；
+//! - CS/SS由STAR的bit32~47给出（kernel code，kernel code + 8）；
+//! - rflags中与SFMASK对应的位会被清除（这里清除IF，即进入内核时关中断）；
+//!
+//! 内核使用SYSRETQ返回用户态，CS/SS由STAR的bit48~63给出（user data - 8 + 16，user data - 8 + 8）。
+//!
+//! SYSCALL不会切换栈，所以入口需要先保存用户栈，再切换到当前线程的内核栈：
+//! 入口先使用swapgs切换到当前CPU的Per-CPU数据，用户栈暂存在gs:[16]，内核栈保存在gs:[8]（见cpu模块）。
+//! 系统调用号保存在rax中，参数依次保存在rdi、rsi、rdx、r10、r8、r9中，返回值保存在rax中。
+//!
+//! SYSRET要求rcx为规范地址，否则在内核态触发#GP（已经切换到用户栈和用户GS，无法处理），
+//! 所以用户地址空间不包含最高的一页（见usermode::USER_END），返回前也会检查rip。
+//! rsp不影响SYSRET，非规范的用户栈在返回用户态之后才会触发异常。
+
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use super::gdt;
+use super::trap::{FAULT_EXIT_CODE, GENERAL_PROTECTION_FAULT};
+use super::usermode::{USER_END, USER_START};
+
+
+global_asm!(r#"
+.global __syscall_entry
+__syscall_entry:
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+    push qword ptr gs:[16]
+    push rcx
+    push r11
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    mov rdi, rsp
+    call syscall_handler
+    cli
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+"#);
+
+extern "C" {
+    fn __syscall_entry();
+}
+
+/// syscall入口保存的用户态寄存器
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// 系统调用号，返回时保存返回值
+    pub rax: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+/// 设置当前CPU的SYSCALL相关的MSR（每个CPU Core都需要设置）
+///
+/// 需要在cpu::init之后调用。
+pub fn init() {
+    let sel = gdt::selectors();
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+    Star::write(sel.user_code, sel.user_data, sel.kernel_code, sel.kernel_data)
+        .expect("invalid GDT layout for SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(__syscall_entry as unsafe extern "C" fn() as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+}
+
+/// syscall的Rust处理函数
+///
+/// 进入时中断是关闭的，处理系统调用时使能中断，使得系统调用可以被抢占。
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    x86_64::instructions::interrupts::enable();
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    let ret = crate::syscall::dispatch(frame.rax as usize, args.map(|a| a as usize));
+    frame.rax = ret as u64;
+
+    // 不能通过SYSRET返回时，按用户态的General Protection Fault终止线程
+    if !(USER_START as u64 .. USER_END as u64).contains(&frame.rip) {
+        println!("syscall returns to invalid address {:#x}", frame.rip);
+        crate::kthread::exit_with(FAULT_EXIT_CODE + GENERAL_PROTECTION_FAULT as i32);
+    }
+}
@@ -0,0 +1,468 @@
+//! CPU Exception处理模块
+//!
+//! 所有的CPU Exception（中断号0~31）、ISA中断（中断号32~47）、Local APIC定时器中断（48）和IPI（49~50）使用汇编实现的入口（__trap_N）：
+//! - 没有错误码的Exception先压入0作为错误码，使得栈上的布局相同；
+//! - 再压入中断号，跳转到__trap_common保存所有通用寄存器，构成TrapFrame，然后调用trap_dispatch；
+//! - trap_dispatch返回后恢复寄存器，跳过中断号和错误码，使用iretq返回；
+//! - 从用户态进入时（栈上CS的RPL为3），进入和返回时都需要swapgs（见cpu模块）。
+//!
+//! NMI和Machine Check不受IF影响，可能发生在syscall入口swapgs之后、切换到内核栈之前，
+//! 此时CS已经是内核态但GS还是用户的，所以它们使用IST栈（见gdt模块），
+//! 并由__trap_paranoid读取IA32_GS_BASE判断是否需要swapgs（内核的GS为高半部分的地址）。
+//! NMI可能打断持有控制台锁的代码，所以NMI处理中只计数，由之后的Timer中断打印。
+//!
+//! 无法恢复的Exception会打印结构化的报告（中断号名称、解码后的错误码、RIP、CR2/CR3、寄存器、调用栈），
+//! 然后由FaultPolicy决定终止当前线程还是panic。
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::{
+    registers::control::{Cr2, Cr3},
+    structures::idt::PageFaultErrorCode,
+    VirtAddr,
+};
+use super::{apic, backtrace, ipi, irq, kstack, vma};
+
+
+global_asm!(r#"
+.macro TRAP_NOERR vector
+__trap_\vector:
+    push 0
+    push \vector
+    jmp __trap_common
+.endm
+
+.macro TRAP_ERR vector
+__trap_\vector:
+    push \vector
+    jmp __trap_common
+.endm
+
+.macro TRAP_PARANOID vector
+__trap_\vector:
+    push 0
+    push \vector
+    jmp __trap_paranoid
+.endm
+
+TRAP_NOERR 0
+TRAP_NOERR 1
+TRAP_PARANOID 2
+TRAP_NOERR 3
+TRAP_NOERR 4
+TRAP_NOERR 5
+TRAP_NOERR 6
+TRAP_NOERR 7
+TRAP_ERR   8
+TRAP_NOERR 9
+TRAP_ERR   10
+TRAP_ERR   11
+TRAP_ERR   12
+TRAP_ERR   13
+TRAP_ERR   14
+TRAP_NOERR 15
+TRAP_NOERR 16
+TRAP_ERR   17
+TRAP_PARANOID 18
+TRAP_NOERR 19
+TRAP_NOERR 20
+TRAP_ERR   21
+TRAP_NOERR 22
+TRAP_NOERR 23
+TRAP_NOERR 24
+TRAP_NOERR 25
+TRAP_NOERR 26
+TRAP_NOERR 27
+TRAP_NOERR 28
+TRAP_ERR   29
+TRAP_ERR   30
+TRAP_NOERR 31
+
+.irp vector, 32,33,34,35,36,37,38,39,40,41,42,43,44,45,46,47,48,49,50
+TRAP_NOERR \vector
+.endr
+
+__trap_common:
+    test qword ptr [rsp + 24], 3
+    jz 1f
+    swapgs
+1:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call trap_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    test qword ptr [rsp + 8], 3
+    jz 2f
+    swapgs
+2:
+    iretq
+
+__trap_paranoid:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    // IA32_GS_BASE的最高位为0时不是内核的GS，需要swapgs，ebx记录返回时是否需要恢复
+    mov ecx, 0xc0000101
+    rdmsr
+    xor ebx, ebx
+    test edx, edx
+    js 1f
+    swapgs
+    mov ebx, 1
+1:
+    mov rdi, rsp
+    cld
+    call trap_dispatch
+    test ebx, ebx
+    jz 2f
+    swapgs
+2:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+
+.section .rodata
+.balign 8
+.global __trap_table
+__trap_table:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31,32,33,34,35,36,37,38,39,40,41,42,43,44,45,46,47,48,49,50
+    .quad __trap_\vector
+.endr
+.text
+"#);
+
+/// 使用trap入口的中断号数量：CPU Exception（0~31）、ISA中断（32~47，见irq模块）、Local APIC定时器（48）和IPI（49~50）
+const TRAP_VECTORS: usize = 51;
+
+extern "C" {
+    static __trap_table: [u64; TRAP_VECTORS];
+}
+
+/// Exception入口保存的寄存器（低地址在前）
+///
+/// 中断号之后的部分由CPU压入（iretq使用），64位模式下总是包含rsp和ss。
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// 是否在用户态（Ring 3）触发
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// Exception的名称，下标为中断号
+const NAMES: [&str; 32] = [
+    "Divide Error", "Debug", "Non-maskable Interrupt", "Breakpoint",
+    "Overflow", "Bound Range Exceeded", "Invalid Opcode", "Device Not Available",
+    "Double Fault", "Coprocessor Segment Overrun", "Invalid TSS", "Segment Not Present",
+    "Stack-Segment Fault", "General Protection Fault", "Page Fault", "Reserved",
+    "x87 Floating-Point Exception", "Alignment Check", "Machine Check", "SIMD Floating-Point Exception",
+    "Virtualization Exception", "Control Protection Exception", "Reserved", "Reserved",
+    "Reserved", "Reserved", "Reserved", "Reserved",
+    "Hypervisor Injection Exception", "VMM Communication Exception", "Security Exception", "Reserved",
+];
+
+pub const DIVIDE_ERROR: u64 = 0;
+pub const DEBUG: u64 = 1;
+pub const NMI: u64 = 2;
+pub const BREAKPOINT: u64 = 3;
+pub const INVALID_OPCODE: u64 = 6;
+pub const DOUBLE_FAULT: u64 = 8;
+pub const INVALID_TSS: u64 = 10;
+pub const SEGMENT_NOT_PRESENT: u64 = 11;
+pub const STACK_SEGMENT_FAULT: u64 = 12;
+pub const GENERAL_PROTECTION_FAULT: u64 = 13;
+pub const PAGE_FAULT: u64 = 14;
+pub const MACHINE_CHECK: u64 = 18;
+
+/// Exception入口的地址
+pub fn handler_addr(vector: u8) -> VirtAddr {
+    VirtAddr::new(unsafe { __trap_table[vector as usize] })
+}
+
+/// 无法恢复的Exception的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    /// 终止当前线程，线程的返回值为FAULT_EXIT_CODE + 中断号（i32）
+    Kill,
+    /// panic
+    Panic,
+}
+
+/// 被终止的线程的返回值基数
+pub const FAULT_EXIT_CODE: i32 = 128;
+
+/// 决定Exception处理方式的函数
+pub type FaultPolicy = fn(&TrapFrame) -> FaultAction;
+
+static POLICY: RwLock<FaultPolicy> = RwLock::new(default_policy);
+
+/// 默认的处理方式：用户态的Exception终止当前线程，内核态的Exception panic
+pub fn default_policy(frame: &TrapFrame) -> FaultAction {
+    if frame.is_user() {
+        FaultAction::Kill
+    } else {
+        FaultAction::Panic
+    }
+}
+
+/// 设置Exception的处理方式，返回之前的FaultPolicy
+///
+/// Double Fault和Machine Check总是panic，不受FaultPolicy影响。
+pub fn set_fault_policy(policy: FaultPolicy) -> FaultPolicy {
+    core::mem::replace(&mut *POLICY.write(), policy)
+}
+
+/// 收到的NMI数量
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+/// 已经打印过的NMI数量
+static NMI_REPORTED: AtomicU64 = AtomicU64::new(0);
+
+/// 收到的NMI数量
+pub fn nmi_count() -> u64 {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+/// 打印上一次打印之后收到的NMI（在Timer中断中调用，多个CPU中只有一个打印）
+fn report_nmi() {
+    let count = NMI_COUNT.load(Ordering::Relaxed);
+    if NMI_REPORTED.swap(count, Ordering::Relaxed) != count {
+        println!("EXCEPTION: Non-maskable Interrupt ({} in total)", count);
+    }
+}
+
+/// 所有Exception和ISA中断的Rust处理函数（中断已关闭）
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    if frame.vector == NMI {
+        // 不能获取任何锁（被打断的代码可能持有控制台等的锁）
+        NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if frame.vector == apic::TIMER_VECTOR as u64 {
+        report_nmi();
+        apic::timer_interrupt();
+        return;
+    }
+    if frame.vector == apic::WAKEUP_VECTOR as u64 {
+        apic::wakeup_interrupt();
+        return;
+    }
+    if frame.vector == apic::CALL_VECTOR as u64 {
+        ipi::call_interrupt();
+        return;
+    }
+    if frame.vector >= irq::IRQ_BASE as u64 {
+        irq::dispatch((frame.vector - irq::IRQ_BASE as u64) as u8);
+        return;
+    }
+    match frame.vector {
+        BREAKPOINT => {
+            println!("EXCEPTION: Breakpoint at {:#x}", frame.rip);
+            return;
+        },
+        DEBUG => {
+            report(frame);
+            return;
+        },
+        PAGE_FAULT => {
+            let code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if vma::handle_page_fault(Cr2::read(), code) {
+                return;
+            }
+        },
+        _ => {},
+    }
+
+    report(frame);
+
+    // 访问内核栈的guard page（Double Fault是因为Page Fault无法压栈）
+    if frame.vector == PAGE_FAULT && !frame.is_user() || frame.vector == DOUBLE_FAULT {
+        if let Some(name) = kstack::guard_hit(Cr2::read()) {
+            panic!("stack overflow in {}", name);
+        }
+    }
+
+    let action = match frame.vector {
+        DOUBLE_FAULT | MACHINE_CHECK => FaultAction::Panic,
+        // FaultPolicy正在被修改时，使用默认的处理方式
+        _ => POLICY.try_read().map_or_else(|| default_policy(frame), |policy| policy(frame)),
+    };
+    match action {
+        FaultAction::Kill => {
+            let thread = crate::kthread::current();
+            println!("killed thread {} ({:?})", thread.name(), thread.id());
+            drop(thread);
+            crate::kthread::exit_with(FAULT_EXIT_CODE + frame.vector as i32);
+        },
+        FaultAction::Panic => panic!("EXCEPTION: {} at {:#x}", NAMES[frame.vector as usize], frame.rip),
+    }
+}
+
+/// 打印Exception的报告
+fn report(frame: &TrapFrame) {
+    let vector = frame.vector as usize;
+    println!("EXCEPTION: {} (#{}) in {} mode", NAMES[vector], vector, if frame.is_user() { "user" } else { "kernel" });
+    match frame.vector {
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            // bit0: 外部事件；bit1~2: 描述符表（0为GDT，1/3为IDT，2为LDT）；bit3~15: 描述符索引
+            let code = frame.error_code;
+            let table = match (code >> 1) & 3 {
+                0 => "GDT",
+                2 => "LDT",
+                _ => "IDT",
+            };
+            if code == 0 {
+                println!("Error Code: 0");
+            } else {
+                println!("Error Code: {:#x} (selector {}[{}]{})", code, table, (code >> 3) & 0x1fff,
+                    if code & 1 != 0 { ", external" } else { "" });
+            }
+        },
+        PAGE_FAULT => {
+            let code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            println!("Error Code: {:?} ({} {})", code,
+                if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) { "protection violation" } else { "page not present" },
+                if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) { "on fetch" }
+                    else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) { "on write" } else { "on read" });
+            report_address(Cr2::read());
+        },
+        DOUBLE_FAULT => println!("CR2: {:?}", Cr2::read()),
+        17 | 21 | 29 | 30 => println!("Error Code: {:#x}", frame.error_code),
+        _ => {},
+    }
+    println!("RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#010x}", frame.rip, frame.cs, frame.rflags);
+    println!("RSP: {:#018x}  SS: {:#06x}  CR3: {:?}", frame.rsp, frame.ss, Cr3::read().0.start_address());
+    println!("RAX: {:#018x}  RBX: {:#018x}  RCX: {:#018x}", frame.rax, frame.rbx, frame.rcx);
+    println!("RDX: {:#018x}  RSI: {:#018x}  RDI: {:#018x}", frame.rdx, frame.rsi, frame.rdi);
+    println!("RBP: {:#018x}  R8:  {:#018x}  R9:  {:#018x}", frame.rbp, frame.r8, frame.r9);
+    println!("R10: {:#018x}  R11: {:#018x}  R12: {:#018x}", frame.r10, frame.r11, frame.r12);
+    println!("R13: {:#018x}  R14: {:#018x}  R15: {:#018x}", frame.r13, frame.r14, frame.r15);
+    if !frame.is_user() {
+        backtrace::print_from(frame.rip, frame.rbp);
+    }
+}
+
+/// 打印Page Fault访问的地址的映射情况
+fn report_address(addr: VirtAddr) {
+    use super::memory::PageTableImpl;
+    println!("Accessed Address: {:?}", addr);
+    match PageTableImpl::active().translate(addr) {
+        Some((phys, flags, size)) => println!("Mapping: {:?} {:?} ({:#x} page)", phys, flags, size),
+        None => println!("Mapping: not mapped"),
+    }
+    match vma::find(addr) {
+        Some(v) => println!("VMA: {} [{:?}, {:?}) {:?}", v.name, v.start, v.end, v.flags),
+        None => println!("VMA: none"),
+    }
+}
+
+
+
+/// 按FaultPolicy终止触发Exception的线程
+#[test_case]
+fn test_fault_policy() {
+    fn kill_test_thread(frame: &TrapFrame) -> FaultAction {
+        if crate::kthread::current().name() == "fault_test" {
+            FaultAction::Kill
+        } else {
+            default_policy(frame)
+        }
+    }
+
+    let old = set_fault_policy(kill_test_thread);
+    let ud = crate::kthread::spawn("fault_test", || {
+        unsafe { core::arch::asm!("ud2") };
+        0i32
+    });
+    assert_eq!(ud.join(), Some(FAULT_EXIT_CODE + INVALID_OPCODE as i32));
+    let gp = crate::kthread::spawn("fault_test", || {
+        // 非canonical地址触发General Protection Fault
+        unsafe { (0x8000_0000_0000_0000 as *const u64).read_volatile() };
+        0i32
+    });
+    assert_eq!(gp.join(), Some(FAULT_EXIT_CODE + GENERAL_PROTECTION_FAULT as i32));
+    set_fault_policy(old);
+}
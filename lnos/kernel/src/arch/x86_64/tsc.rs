@@ -0,0 +1,121 @@
+//! TSC模块
+//!
+//! TSC（Time Stamp Counter）是CPU内的64位计数器，通过rdtsc读取，开销很小且精度为纳秒级；
+//! 但TSC的频率与CPU型号有关，需要在启动时校准（优先使用HPET，没有HPET时使用PIT）。
+//!
+//! 只有不变的TSC（Invariant TSC，不随CPU的频率和节能状态变化）才适合作为时钟源，
+//! 否则注册为低优先级的时钟源（仍然优于PIT）。
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use crate::time::{self, ClockSource};
+use super::{hpet, pit};
+
+
+/// 每次校准的时间（毫秒）
+const CALIBRATE_MS: u32 = 10;
+/// 校准次数，取中位数
+const CALIBRATE_ROUNDS: usize = 3;
+
+/// TSC的时钟源
+pub struct TscClock {
+    frequency: AtomicU64,
+    invariant: AtomicBool,
+}
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency.load(Ordering::Relaxed)
+    }
+
+    fn rating(&self) -> u32 {
+        if self.invariant.load(Ordering::Relaxed) { 300 } else { 100 }
+    }
+}
+
+static TSC_CLOCK: TscClock = TscClock {
+    frequency: AtomicU64::new(0),
+    invariant: AtomicBool::new(false),
+};
+
+/// 读取TSC
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// CPU是否支持TSC
+fn has_tsc() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 4) != 0
+}
+
+/// TSC是否不变（CPUID.80000007H:EDX[8]）
+fn is_invariant() -> bool {
+    let ext = unsafe { __cpuid(0x8000_0000) };
+    ext.eax >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// 校准后的TSC频率（Hz），未校准时为0
+pub fn frequency() -> u64 {
+    TSC_CLOCK.frequency()
+}
+
+/// 使用HPET或PIT测量TSC的频率
+fn measure() -> u64 {
+    interrupts::without_interrupts(|| {
+        hpet::calibrate(CALIBRATE_MS, rdtsc).unwrap_or_else(|| pit::calibrate(CALIBRATE_MS, rdtsc))
+    })
+}
+
+/// 校准TSC并注册为时钟源
+///
+/// 需要在pic::init和hpet::init之后调用。
+pub fn init() {
+    if !has_tsc() {
+        println!("TSC: not supported");
+        return;
+    }
+    let mut samples = [0u64; CALIBRATE_ROUNDS];
+    for sample in samples.iter_mut() {
+        *sample = measure();
+    }
+    samples.sort_unstable();
+    let frequency = samples[CALIBRATE_ROUNDS / 2];
+    if frequency == 0 {
+        println!("TSC: calibration failed");
+        return;
+    }
+    TSC_CLOCK.frequency.store(frequency, Ordering::Relaxed);
+    let invariant = is_invariant();
+    TSC_CLOCK.invariant.store(invariant, Ordering::Relaxed);
+    println!("TSC: {}.{:03} MHz{}", frequency / 1_000_000, frequency / 1000 % 1000,
+        if invariant { " (invariant)" } else { "" });
+    time::register_clock_source(&TSC_CLOCK);
+}
+
+
+
+/// 重新测量的TSC频率与校准的频率一致
+#[test_case]
+fn test_tsc() {
+    if frequency() == 0 {
+        return;
+    }
+    let measured = measure();
+    let diff = if measured > frequency() { measured - frequency() } else { frequency() - measured };
+    // 误差不超过5%（qemu中TSC受宿主机调度影响）
+    assert!(diff < frequency() / 20, "{} vs {}", measured, frequency());
+    // 使用rating最高的时钟源：不变的TSC优先于HPET，否则HPET（若存在）优先于TSC
+    assert!(time::clock_source_rating() >= Some(TSC_CLOCK.rating()));
+    if TSC_CLOCK.invariant.load(Ordering::Relaxed) {
+        assert_eq!(time::clock_source_name(), Some("tsc"));
+    }
+}
@@ -0,0 +1,163 @@
+//! 用户态模块
+//!
+//! 内核通过iretq进入用户态（Ring 3）：在栈上依次压入SS、RSP、RFLAGS、CS、RIP，
+//! 其中CS和SS为GDT中的user code和user data（RPL = 3）。
+//!
+//! 用户态通过syscall（见syscall模块）或中断返回内核态。
+
+use core::arch::asm;
+use x86_64::VirtAddr;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{PageTableFlags, Translate, mapper::TranslateResult};
+use super::gdt;
+use super::memory::PageTableImpl;
+
+
+/// 用户地址空间的下限（L4[1]，L4[0]为kernel代码）
+pub const USER_START: usize = 0x0000_0080_0000_0000;
+/// 用户地址空间的上限（低半部分的规范地址，最高的一页保留不映射）
+///
+/// 最高一页末尾的syscall的返回地址（rcx）为非规范地址，SYSRET会在内核态触发#GP，
+/// 而此时已经切换到用户栈和用户GS（见syscall模块）。
+pub const USER_END: usize = 0x0000_7FFF_FFFF_F000;
+
+/// 从当前线程进入用户态，从entry处开始执行，用户栈为stack
+///
+/// 进入用户态后，线程的内核栈从栈顶开始复用（syscall和中断均从栈顶开始），
+/// 所以enter_user不会返回，用户程序通过exit系统调用结束线程。
+pub fn enter_user(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let sel = gdt::selectors();
+    let rflags = RFlags::INTERRUPT_FLAG.bits() | 0x2; // bit1为保留位，始终为1
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            // 清除通用寄存器，防止内核数据泄漏到用户态
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            // 用户态使用自己的GS，关中断后再swapgs，防止中断入口看到内核态的CS而不交换
+            "cli",
+            "swapgs",
+            "iretq",
+            ss = in(reg) sel.user_data.0 as u64,
+            rsp = in(reg) stack.as_u64(),
+            rflags = in(reg) rflags,
+            cs = in(reg) sel.user_code.0 as u64,
+            rip = in(reg) entry.as_u64(),
+            options(noreturn),
+        );
+    }
+}
+
+/// 检查[addr, addr + len)是否为用户态可以访问的内存
+///
+/// 系统调用访问用户态传入的指针之前，需要先检查，防止用户态借助内核访问内核内存。
+pub fn check_user_range(addr: usize, len: usize, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match addr.checked_add(len) {
+        Some(end) if addr >= USER_START && end <= USER_END => end,
+        _ => return false,
+    };
+
+    let pg = PageTableImpl::active();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    (addr & !0xfff .. end)
+        .step_by(0x1000)
+        .all(|page| match pg.mapper.translate(VirtAddr::new(page as u64)) {
+            TranslateResult::Mapped { flags: f, .. } => f.contains(flags),
+            _ => false,
+        })
+}
+
+
+
+#[cfg(test)]
+core::arch::global_asm!(r#"
+.global __user_test_start
+.global __user_test_end
+__user_test_start:
+    mov rax, 39
+    syscall
+    mov rax, 1
+    mov rdi, 1
+    lea rsi, [rip + __user_test_msg]
+    mov rdx, __user_test_msg_end - __user_test_msg
+    syscall
+    mov rax, 24
+    syscall
+    mov rax, 60
+    mov rdi, 42
+    syscall
+    ud2
+__user_test_msg:
+    .ascii "hello from ring 3\n"
+__user_test_msg_end:
+__user_test_end:
+"#);
+
+/// 在用户态执行一段代码：getpid、write、sched_yield，最后以42退出
+#[test_case]
+fn test_user_mode() {
+    use alloc::sync::Arc;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use super::addr_space::AddressSpace;
+
+    extern "C" {
+        static __user_test_start: u8;
+        static __user_test_end: u8;
+    }
+    const USER_TEST_ADDR: u64 = 0x0000_4000_0000_0000;
+
+    let code = unsafe {
+        let start = &__user_test_start as *const u8;
+        let len = &__user_test_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+
+    // 在单独的地址空间中映射代码页（R+X）和栈页（RW+NX）
+    let entry = VirtAddr::new(USER_TEST_ADDR);
+    let stack = entry + 0x2000u64;
+    let mut space = AddressSpace::new().expect("out of memory");
+    space.map(entry, 0x1000, Flags::USER_ACCESSIBLE).expect("failed to map code");
+    space.map(entry + 0x1000u64, 0x1000, Flags::USER_ACCESSIBLE | Flags::WRITABLE | Flags::NO_EXECUTE)
+        .expect("failed to map stack");
+    space.write(entry, code).expect("failed to write code");
+    let space = Arc::new(space);
+
+    let user_space = space.clone();
+    let handle = crate::kthread::spawn("user", move || -> i32 {
+        crate::kthread::set_address_space(Some(user_space));
+        enter_user(entry, stack)
+    });
+    assert_eq!(handle.join(), Some(42));
+
+    // 在当前线程中切换到该地址空间，检查用户态可以访问的范围
+    crate::kthread::set_address_space(Some(space));
+    assert!(check_user_range(USER_TEST_ADDR as usize, 0x2000, false));
+    assert!(check_user_range(USER_TEST_ADDR as usize + 0x1000, 0x1000, true));
+    assert!(!check_user_range(USER_TEST_ADDR as usize, 0x2000, true));
+    assert!(!check_user_range(USER_TEST_ADDR as usize, 0x3000, false));
+    // 切换回内核的地址空间，释放该地址空间
+    crate::kthread::set_address_space(None);
+}
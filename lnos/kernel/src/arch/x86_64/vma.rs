@@ -0,0 +1,182 @@
+//! 虚拟内存区域（VMA）模块
+//!
+//! 内核空间中注册的VMA在访问时才分配物理Frame（Demand Paging）：
+//! 访问VMA中未映射的page时触发Page Fault，由handle_page_fault分配清零的Frame并映射，
+//! 然后返回到触发Page Fault的指令重新执行。
+//!
+//! Page Fault可能发生在持有堆分配器锁的时候，所以VMA使用固定大小的表，查找和映射过程中不使用堆内存。
+//!
+//! 多个CPU可能同时访问同一个未映射的page：后映射的CPU发现page已经以兼容的flags映射时，
+//! 释放自己分配的Frame，直接返回重新执行。
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+        Translate, mapper::{MapToError, TranslateResult},
+    },
+    VirtAddr,
+};
+use super::memory::{phys_to_virt, GlobalFrameAllocator, PageTableImpl};
+
+
+/// 最多可以注册的VMA数量
+pub const MAX_VMAS: usize = 32;
+
+/// 内核空间的起始地址
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+/// 虚拟内存区域[start, end)
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    /// 映射page时使用的flags
+    pub flags: PageTableFlags,
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// VMA注册错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// 地址没有按page对齐，或不在内核空间中
+    BadRange,
+    /// 与已注册的VMA重叠
+    Overlap,
+    /// VMA表已满
+    TableFull,
+    /// 没有找到VMA
+    NotFound,
+}
+
+/// VMA表只能在关中断时访问（Page Fault中也会访问）
+static VMAS: Mutex<[Option<Vma>; MAX_VMAS]> = Mutex::new([None; MAX_VMAS]);
+
+/// 注册[start, start + size)为按需映射的VMA
+pub fn register(start: VirtAddr, size: usize, flags: PageTableFlags, name: &'static str) -> Result<(), VmaError> {
+    let end = start.as_u64().checked_add(size as u64).ok_or(VmaError::BadRange)?;
+    if size == 0 || !start.is_aligned(Size4KiB::SIZE) || end % Size4KiB::SIZE != 0
+        || start.as_u64() < KERNEL_SPACE_START {
+        return Err(VmaError::BadRange);
+    }
+    let vma = Vma {
+        start,
+        end: VirtAddr::new(end),
+        flags: flags | PageTableFlags::PRESENT,
+        name,
+    };
+    interrupts::without_interrupts(|| {
+        let mut vmas = VMAS.lock();
+        if vmas.iter().flatten().any(|v| v.start < vma.end && vma.start < v.end) {
+            return Err(VmaError::Overlap);
+        }
+        let slot = vmas.iter_mut().find(|v| v.is_none()).ok_or(VmaError::TableFull)?;
+        *slot = Some(vma);
+        Ok(())
+    })
+}
+
+/// 注销start处的VMA，并释放已经映射的Frame
+pub fn unregister(start: VirtAddr) -> Result<(), VmaError> {
+    let vma = interrupts::without_interrupts(|| {
+        VMAS.lock()
+            .iter_mut()
+            .find(|v| v.map_or(false, |v| v.start == start))
+            .and_then(|v| v.take())
+    }).ok_or(VmaError::NotFound)?;
+
+    let mut pg = PageTableImpl::active();
+    for (phys, _, _) in pg.unmap_range(vma.start, vma.end - vma.start) {
+        unsafe { GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)) };
+    }
+    Ok(())
+}
+
+/// 查找包含addr的VMA
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    interrupts::without_interrupts(|| VMAS.lock().iter().flatten().find(|v| v.contains(addr)).copied())
+}
+
+/// 处理Page Fault，返回true表示已经为addr映射了page，可以返回重新执行
+///
+/// 只处理访问VMA中未映射page的情况，权限错误（PROTECTION_VIOLATION）需要由调用者报告。
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    // VMAS只在关中断时持有，且持有期间不会访问VMA中的内存，所以Page Fault中可以等待其它CPU释放
+    let vma = match VMAS.lock().iter().flatten().find(|v| v.contains(addr)).copied() {
+        Some(vma) => vma,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::USER_MODE) && !vma.flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return false;
+    }
+
+    let mut pg = PageTableImpl::active();
+    let page = Page::<Size4KiB>::containing_address(addr);
+    if let Some(compatible) = mapped_with(&pg, page, vma.flags) {
+        // 已经被其它CPU映射，直接返回重新执行
+        return compatible;
+    }
+    let frame = match GlobalFrameAllocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+    }
+    match pg.try_map(page, frame, vma.flags) {
+        Ok(()) => true,
+        Err(err) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            matches!(err, MapToError::PageAlreadyMapped(_)) && mapped_with(&pg, page, vma.flags) == Some(true)
+        },
+    }
+}
+
+/// page已经映射时，返回其flags是否包含flags（即与VMA兼容）；未映射时返回None
+fn mapped_with(pg: &PageTableImpl, page: Page, flags: PageTableFlags) -> Option<bool> {
+    match pg.mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { flags: old, .. } => Some(old.contains(flags)),
+        _ => None,
+    }
+}
+
+
+
+/// 访问VMA时按需映射，注销时释放
+#[test_case]
+fn test_demand_paging() {
+    let start = super::vmalloc::reserve(0x4000, Size4KiB::SIZE).expect("vmalloc reserve failed");
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    register(start, 0x4000, flags, "test").unwrap();
+    assert_eq!(register(start + 0x1000u64, 0x1000, flags, "test"), Err(VmaError::Overlap));
+    assert_eq!(register(VirtAddr::new(0x1000), 0x1000, flags, "test"), Err(VmaError::BadRange));
+
+    let pg = PageTableImpl::active();
+    assert!(pg.mapper.translate_addr(start + 0x2000u64).is_none());
+    unsafe {
+        let ptr = (start + 0x2008u64).as_mut_ptr::<u64>();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert!(pg.mapper.translate_addr(start + 0x2000u64).is_some());
+    assert!(pg.mapper.translate_addr(start).is_none());
+    assert_eq!(find(start + 0x3fffu64).map(|v| v.name), Some("test"));
+    assert!(find(start + 0x4000u64).is_none());
+
+    unregister(start).unwrap();
+    assert!(pg.mapper.translate_addr(start + 0x2000u64).is_none());
+    assert!(find(start).is_none());
+    super::vmalloc::release(start).expect("vmalloc release failed");
+}
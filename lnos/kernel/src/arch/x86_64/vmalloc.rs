@@ -0,0 +1,224 @@
+//! 内核虚拟地址分配模块（vmalloc）
+//!
+//! 在内核空间的VMALLOC区域中分配互不重叠的虚拟地址范围：
+//! - vmalloc：分配虚拟地址并映射新分配的清零Frame，vfree时一起释放；
+//! - vmap：将给定的Frame映射到连续的虚拟地址，vfree时只取消映射，不释放Frame；
+//! - reserve/release：只分配虚拟地址，由调用者自行映射（如ioremap）。
+//!
+//! 每个范围之后保留一个不映射的guard page，区域的开头也保留一个guard page，
+//! 所以越界访问会触发Page Fault，而不会访问到相邻的范围。
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+use super::memory::{phys_to_virt, GlobalFrameAllocator, PageTableImpl};
+
+
+/// VMALLOC区域
+pub const VMALLOC_START: u64 = 0xffff_e000_0000_0000;
+pub const VMALLOC_SIZE: u64 = 0x100_0000_0000;
+
+/// guard page的大小
+const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+/// vmalloc错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmallocError {
+    /// 大小为0
+    BadSize,
+    /// VMALLOC区域没有足够的虚拟地址
+    NoVirtualSpace,
+    /// 没有可用的物理内存
+    OutOfMemory,
+    /// 地址不是已分配范围的起始地址
+    NotFound,
+}
+
+/// 范围的映射方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AreaKind {
+    /// 由vmalloc分配的Frame，vfree时释放
+    Owned,
+    /// 由vmap映射的Frame，vfree时不释放
+    Mapped,
+    /// 只分配了虚拟地址
+    Reserved,
+}
+
+/// 已分配的范围[start, start + size)，之后的guard page不计入size
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    start: u64,
+    size: u64,
+    kind: AreaKind,
+}
+
+/// 已分配的范围，按start排序
+static AREAS: Mutex<Vec<Area>> = Mutex::new(Vec::new());
+
+/// 分配按align对齐、大小为size的虚拟地址范围
+fn alloc_area(size: u64, align: u64, kind: AreaKind) -> Result<VirtAddr, VmallocError> {
+    if size == 0 {
+        return Err(VmallocError::BadSize);
+    }
+    let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+    let align = align.max(Size4KiB::SIZE);
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let mut start = (VMALLOC_START + GUARD_SIZE + align - 1) & !(align - 1);
+        let mut index = areas.len();
+        for (k, area) in areas.iter().enumerate() {
+            if start + size + GUARD_SIZE <= area.start {
+                index = k;
+                break;
+            }
+            start = (area.start + area.size + GUARD_SIZE + align - 1) & !(align - 1);
+        }
+        if start + size + GUARD_SIZE > VMALLOC_START + VMALLOC_SIZE {
+            return Err(VmallocError::NoVirtualSpace);
+        }
+        areas.insert(index, Area { start, size, kind });
+        Ok(VirtAddr::new(start))
+    })
+}
+
+/// 查找start处的范围
+fn find_area(start: VirtAddr) -> Result<Area, VmallocError> {
+    interrupts::without_interrupts(|| {
+        AREAS.lock().iter().find(|a| a.start == start.as_u64()).copied().ok_or(VmallocError::NotFound)
+    })
+}
+
+/// 移除start处的范围
+fn free_area(start: VirtAddr) -> Result<Area, VmallocError> {
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let index = areas.iter().position(|a| a.start == start.as_u64()).ok_or(VmallocError::NotFound)?;
+        Ok(areas.remove(index))
+    })
+}
+
+/// 只分配虚拟地址，不映射
+pub fn reserve(size: usize, align: u64) -> Result<VirtAddr, VmallocError> {
+    alloc_area(size as u64, align, AreaKind::Reserved)
+}
+
+/// 释放由reserve分配的虚拟地址，调用者需要已经取消映射
+pub fn release(start: VirtAddr) -> Result<(), VmallocError> {
+    free_area(start).map(|_| ())
+}
+
+/// 分配size大小的内核虚拟内存，并映射清零的Frame
+pub fn vmalloc(size: usize, flags: PageTableFlags) -> Result<VirtAddr, VmallocError> {
+    let start = alloc_area(size as u64, Size4KiB::SIZE, AreaKind::Owned)?;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let count = (size as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let mut pg = PageTableImpl::active();
+    for k in 0 .. count {
+        let frame: Option<PhysFrame> = GlobalFrameAllocator.allocate_frame();
+        let mapped = frame.map_or(false, |frame| {
+            unsafe {
+                core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+            }
+            pg.try_map(first + k, frame, flags | PageTableFlags::PRESENT)
+                .map_err(|_| unsafe { GlobalFrameAllocator.deallocate_frame(frame) })
+                .is_ok()
+        });
+        if !mapped {
+            // 回滚已经映射的page（Frame或中间页表的Frame不足）
+            vfree(start)?;
+            return Err(VmallocError::OutOfMemory);
+        }
+    }
+    Ok(start)
+}
+
+/// 将frames映射到连续的内核虚拟地址
+pub fn vmap(frames: &[PhysFrame], flags: PageTableFlags) -> Result<VirtAddr, VmallocError> {
+    let size = frames.len() as u64 * Size4KiB::SIZE;
+    let start = alloc_area(size, Size4KiB::SIZE, AreaKind::Mapped)?;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let mut pg = PageTableImpl::active();
+    for (k, &frame) in frames.iter().enumerate() {
+        if pg.try_map(first + k as u64, frame, flags | PageTableFlags::PRESENT).is_err() {
+            // 中间页表的Frame不足，回滚已经映射的page
+            vfree(start)?;
+            return Err(VmallocError::OutOfMemory);
+        }
+    }
+    Ok(start)
+}
+
+/// 释放由vmalloc或vmap分配的范围
+///
+/// 先取消映射，再释放虚拟地址，防止其它CPU在取消映射之前重新分配该范围。
+pub fn vfree(start: VirtAddr) -> Result<(), VmallocError> {
+    let area = find_area(start)?;
+    assert!(area.kind != AreaKind::Reserved, "vfree on a reserved range, use release");
+    let unmapped = PageTableImpl::active().unmap_range(VirtAddr::new(area.start), area.size);
+    if area.kind == AreaKind::Owned {
+        for (phys, _, _) in unmapped {
+            unsafe { GlobalFrameAllocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)) };
+        }
+    }
+    free_area(start).map(|_| ())
+}
+
+/// addr所在的已分配范围的起始地址和大小
+pub fn find(addr: VirtAddr) -> Option<(VirtAddr, usize)> {
+    interrupts::without_interrupts(|| {
+        AREAS.lock()
+            .iter()
+            .find(|a| a.start <= addr.as_u64() && addr.as_u64() < a.start + a.size)
+            .map(|a| (VirtAddr::new(a.start), a.size as usize))
+    })
+}
+
+
+
+/// 分配的范围互不重叠，之间有guard page，释放时归还Frame
+#[test_case]
+fn test_vmalloc() {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let a = vmalloc(0x3000, flags).unwrap();
+    let b = vmalloc(0x1800, flags).unwrap();
+    assert!(a + 0x3000u64 < b || b + 0x2000u64 < a);
+    unsafe {
+        let ptr = (a + 0x2ff8u64).as_mut_ptr::<u64>();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0x1234);
+        assert_eq!(ptr.read_volatile(), 0x1234);
+    }
+
+    let pg = PageTableImpl::active();
+    assert!(pg.translate(a + 0x3000u64).is_none()); // guard page
+    assert_eq!(find(a + 0x2fffu64), Some((a, 0x3000)));
+    assert_eq!(find(a + 0x3000u64), None);
+
+    // 每个page映射到不同的Frame，释放时全部取消映射（其它CPU可能同时分配Frame，所以不检查全局的统计信息）
+    let pages: Vec<VirtAddr> = (0 .. 3u64).map(|k| a + k * Size4KiB::SIZE)
+        .chain((0 .. 2u64).map(|k| b + k * Size4KiB::SIZE))
+        .collect();
+    let mut frames: Vec<_> = pages.iter().map(|&page| pg.translate(page).expect("page not mapped").0).collect();
+    frames.sort();
+    frames.dedup();
+    assert_eq!(frames.len(), pages.len());
+    vfree(a).unwrap();
+    vfree(b).unwrap();
+    assert_eq!(vfree(a), Err(VmallocError::NotFound));
+    assert!(pages.iter().all(|&page| pg.translate(page).is_none()));
+
+    // vmap不释放Frame
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().unwrap();
+    let c = vmap(&[frame], flags).unwrap();
+    unsafe { c.as_mut_ptr::<u64>().write_volatile(0x5678) };
+    assert_eq!(unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read_volatile() }, 0x5678);
+    vfree(c).unwrap();
+    assert!(pg.translate(c).is_none());
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
@@ -1,73 +1,335 @@
 use super::task::{Task, TaskId};
 use alloc::{
-    collections::BTreeMap,
+    collections::VecDeque,
     sync::Arc,
     task::Wake,
 };
-use core::task::{
-    Waker, Context, Poll,
+use core::{
+    future::Future,
+    pin::pin,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+    task::{Waker, Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use crate::arch::{apic, context::CatchPoint, cpu::{self, MAX_CPUS}, ipi::{self, IpiTarget}};
 
 
-/// Executor是系统所有Task的调度器；
+/// 每个CPU的run queue的容量
+const RUN_QUEUE_SIZE: usize = 256;
+
+/// 每个CPU的Executor的run queue，其它CPU也可以访问；
 ///
-/// - tasks: 保存系统所有的task实例，由id索引
-/// - wakers: 保存task的waker，由id索引
-/// - task_queue: waker将id放入queue并且唤醒task，executor从queue取出id并执行对应的task
-pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    wakers: BTreeMap<TaskId, Waker>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+/// - queue: 就绪的task，waker将task放入其上一次执行的CPU的queue，空闲的CPU可以从其它CPU的queue窃取task
+/// - idle: Executor没有可以执行的task，正在（或即将）hlt，放入task后需要发送唤醒IPI
+struct RunQueue {
+    queue: ArrayQueue<Arc<Task>>,
+    idle: AtomicBool,
+}
+
+/// 所有CPU的run queue，下标为CPU编号（在该CPU上创建Executor时初始化）
+static RUN_QUEUES: [Once<RunQueue>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: Once<RunQueue> = Once::new();
+    [NONE; MAX_CPUS]
+};
+
+/// 全局队列：目标CPU没有Executor或run queue已满时，task放入这里，由任意CPU的Executor执行
+///
+/// 中断中也会访问（唤醒task），只能在关中断时访问。
+static INJECTOR: Mutex<VecDeque<Arc<Task>>> = Mutex::new(VecDeque::new());
+
+fn run_queue(cpu: usize) -> Option<&'static RunQueue> {
+    RUN_QUEUES.get(cpu).and_then(Once::get)
+}
+
+fn push_injector(task: Arc<Task>) {
+    interrupts::without_interrupts(|| INJECTOR.lock().push_back(task));
+}
+
+fn pop_injector() -> Option<Arc<Task>> {
+    interrupts::without_interrupts(|| INJECTOR.lock().pop_front())
+}
+
+/// 是否有等待执行的task（任意CPU的run queue或全局队列中）
+fn has_work() -> bool {
+    RUN_QUEUES.iter().filter_map(Once::get).any(|rq| !rq.queue.is_empty())
+        || interrupts::without_interrupts(|| !INJECTOR.lock().is_empty())
+}
+
+/// 将task放入编号为cpu的CPU的run queue，并唤醒空闲的Executor
+fn enqueue(cpu: usize, task: Arc<Task>) {
+    match run_queue(cpu) {
+        Some(rq) => {
+            if let Err(task) = rq.queue.push(task) {
+                push_injector(task);
+            }
+        },
+        None => push_injector(task),
+    }
+    notify(cpu);
+}
+
+/// 唤醒处于idle的Executor：优先唤醒target；target正忙时，唤醒任意一个空闲的CPU来窃取task
+///
+/// 当前CPU不需要IPI（正在执行，或刚被唤醒task的中断唤醒）。
+fn notify(target: usize) {
+    let idle = |cpu: usize| run_queue(cpu).map_or(false, |rq| rq.idle.load(Ordering::SeqCst));
+    let cpu = if idle(target) {
+        Some(target)
+    } else {
+        (0 .. MAX_CPUS).find(|&cpu| idle(cpu))
+    };
+    if let Some(cpu) = cpu.filter(|&cpu| cpu != cpu::id()) {
+        ipi::send(IpiTarget::Cpu(cpu), apic::WAKEUP_VECTOR);
+    }
+}
+
+/// 将新建的task放入当前CPU的run queue（当前CPU没有运行Executor时放入全局队列）
+///
+/// 只放入队列，不会立即执行，所以也可以在中断处理函数中调用。
+pub(super) fn spawn_task(task: Arc<Task>) {
+    let cpu = cpu::id();
+    task.scheduled.store(true, Ordering::SeqCst);
+    task.cpu.store(cpu, Ordering::Relaxed);
+    enqueue(cpu, task);
+}
+
+/// 没有执行task的线程
+const NO_THREAD: u64 = u64::MAX;
+
+/// 正在执行task的线程及其捕获点，task panic时由panic_handler返回到Executor（见end_task_panic）
+///
+/// - thread: 执行task的线程的id，为NO_THREAD时表示空闲
+/// - point: poll之前的捕获点（位于执行task的线程的栈上）
+/// - task: 正在执行的task，panic时取走（防止处理panic时再次panic）
+///
+/// 线程执行task期间可能被抢占，所以按线程而不是CPU查找。
+struct CatchSlot {
+    thread: AtomicU64,
+    point: AtomicPtr<CatchPoint>,
+    task: AtomicPtr<Task>,
+}
+
+/// 所有槽都被占用时，poll不捕获panic（panic会导致kernel panic）
+static CATCH_SLOTS: [CatchSlot; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: CatchSlot = CatchSlot {
+        thread: AtomicU64::new(NO_THREAD),
+        point: AtomicPtr::new(ptr::null_mut()),
+        task: AtomicPtr::new(ptr::null_mut()),
+    };
+    [FREE; MAX_CPUS]
+};
+
+/// 当前线程的CatchSlot
+fn current_slot() -> Option<&'static CatchSlot> {
+    // cpu::init之前不能访问当前线程
+    if cpu::count() == 0 {
+        return None;
+    }
+    let thread = cpu::this_cpu().current_thread();
+    CATCH_SLOTS.iter().find(|slot| slot.thread.load(Ordering::Acquire) == thread)
+}
+
+/// panic时调用：当前线程正在执行task，并且可以安全地终止该task时，返回该task的id
+///
+/// enabled为panic时是否开中断：内核的锁（与中断共享的SCHEDULER、VMAS、堆、控制台等）只在关中断时持有，
+/// 中断和Exception处理函数也在关中断时执行，所以只有开中断时panic，才能确定不是在这些锁中或中断处理中panic；
+/// 关中断时panic返回None（按kernel panic处理）。
+/// 同一次poll只返回一次，处理panic时再次panic返回None。
+pub(crate) fn begin_task_panic(enabled: bool) -> Option<TaskId> {
+    if !enabled {
+        return None;
+    }
+    let task = current_slot()?.task.swap(ptr::null_mut(), Ordering::AcqRel);
+    unsafe { task.as_ref() }.map(Task::id)
+}
+
+/// 终止正在执行的task，返回到Executor继续执行（在begin_task_panic返回Some之后调用）
+pub(crate) fn end_task_panic() -> ! {
+    let slot = current_slot().expect("no task to unwind");
+    unsafe { CatchPoint::throw(slot.point.load(Ordering::Acquire)) }
+}
+
+/// 执行一次task：被abort时释放Future，否则poll（panic过的task不再执行）
+///
+/// 返回None表示task正在其它CPU上执行。
+fn step(task: &Task, cx: &mut Context) -> Option<()> {
+    if task.is_panicked() {
+        Some(())
+    } else if task.is_aborted() {
+        task.cancel()
+    } else {
+        task.poll(cx).map(|_| ())
+    }
+}
+
+/// 通过CatchPoint调用step的参数
+struct Step<'a, 'b> {
+    task: &'a Task,
+    cx: &'a mut Context<'b>,
+    result: Option<()>,
+}
+
+extern "C" fn step_entry(arg: usize) {
+    let step = unsafe { &mut *(arg as *mut Step) };
+    step.result = self::step(step.task, step.cx);
 }
 
-/// TaskWaker用于唤醒task，本质是通知Executor执行queue中的task。
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+/// 执行一次task，并捕获其中的panic：panic时将task标记为panicked
+///
+/// 返回None表示task正在其它CPU上执行。
+fn step_catching(task: &Arc<Task>, cx: &mut Context) -> Option<()> {
+    let thread = cpu::this_cpu().current_thread();
+    let slot = match CATCH_SLOTS.iter().find(|slot| {
+        slot.thread.compare_exchange(NO_THREAD, thread, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+    }) {
+        Some(slot) => slot,
+        None => return step(task, cx),
+    };
+    let mut point = CatchPoint::default();
+    slot.point.store(&mut point, Ordering::Release);
+    slot.task.store(Arc::as_ptr(task) as *mut Task, Ordering::Release);
+
+    let enabled = interrupts::are_enabled();
+    let mut args = Step { task, cx, result: None };
+    let panicked = unsafe { point.catch(step_entry, &mut args as *mut Step as usize) };
+
+    slot.task.store(ptr::null_mut(), Ordering::Release);
+    slot.point.store(ptr::null_mut(), Ordering::Release);
+    slot.thread.store(NO_THREAD, Ordering::Release);
+    if panicked {
+        // panic_handler已经关中断
+        if enabled {
+            interrupts::enable();
+        }
+        task.set_panicked();
+        return Some(());
+    }
+    args.result
+}
+
+/// Executor是每个CPU上的Task调度器，每个CPU最多运行一个Executor；
+///
+/// - cpu: Executor所在的CPU，task执行后记录在task中，被唤醒时放回该CPU的run queue
+///
+/// task由run queue和waker持有（Arc），执行完毕且没有waker时释放。
+/// 本地的run queue为空时，依次从全局队列和其它CPU的run queue（窃取一半）获取task；
+/// 所有队列都为空时hlt，由中断或其它CPU的唤醒IPI唤醒。
+pub struct Executor {
+    cpu: usize,
 }
 
 impl Executor {
+    /// 创建当前CPU的Executor（run queue在第一次创建时初始化）
     pub fn new() -> Self {
-        Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            wakers: BTreeMap::new(),
-        }
+        let cpu = cpu::id();
+        RUN_QUEUES[cpu].call_once(|| RunQueue {
+            queue: ArrayQueue::new(RUN_QUEUE_SIZE),
+            idle: AtomicBool::new(false),
+        });
+        Executor { cpu }
     }
 
-    /// 添加task，并开始调度
+    fn run_queue(&self) -> &'static RunQueue {
+        run_queue(self.cpu).expect("run queue not initialized")
+    }
+
+    /// 添加task到当前CPU的run queue，并开始调度
     pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("({:?}) already in tasks", task_id);
-        }
-        self.task_queue.push(task_id).expect("queue full");
+        spawn_task(Arc::new(task));
+    }
+
+    /// 获取下一个要执行的task：本地run queue、全局队列、其它CPU的run queue
+    fn next_task(&self) -> Option<Arc<Task>> {
+        self.run_queue().queue.pop()
+            .or_else(pop_injector)
+            .or_else(|| self.steal())
     }
 
-    /// 调度处于ready状态的task（即放置在task_queue中的task）
-    fn run_ready_tasks(&mut self) {
-        // 解构self成员，每个成员有各自的&mut
-        let Self { tasks, task_queue, wakers } = self;
+    /// 从其它CPU的run queue窃取task：取出一个执行，再将剩余task的一半移到本地run queue
+    fn steal(&self) -> Option<Arc<Task>> {
+        let local = self.run_queue();
+        (1 .. MAX_CPUS)
+            .filter_map(|k| run_queue((self.cpu + k) % MAX_CPUS))
+            .find_map(|victim| {
+                let task = victim.queue.pop()?;
+                for _ in 0 .. victim.queue.len() / 2 {
+                    match victim.queue.pop().map(|t| local.queue.push(t)) {
+                        Some(Ok(())) => {},
+                        Some(Err(t)) => {
+                            push_injector(t);
+                            break;
+                        },
+                        None => break,
+                    }
+                }
+                Some(task)
+            })
+    }
 
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task, // 取对id对应的task
-                None => continue, // task_id没有对应的task
-            };
+    /// 执行task：task的waker即是task本身
+    fn run_task(&self, task: Arc<Task>) {
+        task.cpu.store(self.cpu, Ordering::Relaxed);
+        // 先清除scheduled，poll期间被唤醒时可以重新放入run queue
+        task.scheduled.store(false, Ordering::SeqCst);
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+        if step_catching(&task, &mut cx).is_none() {
+            // 上一次唤醒的poll还在执行（执行的线程可能被抢占），由其poll结束后重新唤醒task，
+            // 而不是放回run queue反复重试；设置repoll后再尝试一次，防止其恰好已经检查过repoll
+            task.repoll.store(true, Ordering::SeqCst);
+            if step_catching(&task, &mut cx).is_none() {
+                return;
+            }
+        }
+        if task.repoll.swap(false, Ordering::SeqCst) {
+            task.wake_by_ref();
+        }
+    }
 
-            let waker = wakers
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+    /// 调度所有可以执行的task（包括从其它CPU窃取的task），直到没有可以执行的task
+    pub fn run_ready_tasks(&mut self) {
+        while let Some(task) = self.next_task() {
+            self.run_task(task);
+        }
+    }
 
-            // poll task所需要的上下文，即是task的waker
-            match task.poll(&mut Context::from_waker(waker)) {
-                Poll::Ready(()) => { // 移除执行完毕的task和waker
-                    tasks.remove(&task_id);
-                    wakers.remove(&task_id);
+    /// 在当前线程中执行future直到完成，等待期间执行run queue中的task
+    ///
+    /// future只在当前线程中执行，所以不需要Send（如测试中等待JoinHandle）。
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        struct FlagWaker(AtomicBool);
+        impl Wake for FlagWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let flag = Arc::new(FlagWaker(AtomicBool::new(true)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if flag.0.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
                 }
-                Poll::Pending => {}
             }
+            self.run_ready_tasks();
+            core::hint::spin_loop();
+        }
+    }
+
+    /// 调度task直到done返回true（测试中等待可能被其它CPU的Executor执行的task）
+    #[cfg(test)]
+    pub(super) fn run_until(&mut self, done: impl Fn() -> bool) {
+        while !done() {
+            self.run_ready_tasks();
+            core::hint::spin_loop();
         }
     }
 
@@ -81,42 +343,67 @@ impl Executor {
 
     #[cfg(target_arch = "x86_64")]
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts::enable_and_hlt;
 
-        // 防止刚检查完is_empty()，立马来一个中断，导致不能及时响应
+        // 先设置idle再检查队列：其它CPU放入task后检查idle，两者至少有一方能发现对方（均为SeqCst）；
+        // 关中断防止刚检查完has_work()，立马来一个中断，导致不能及时响应
+        let rq = self.run_queue();
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
+        rq.idle.store(true, Ordering::SeqCst);
+        if has_work() {
             interrupts::enable();
+        } else {
+            enable_and_hlt();
         }
+        rq.idle.store(false, Ordering::SeqCst);
     }
 }
 
-impl TaskWaker {
-    /// 这里的task_queue即是Executor::task_queue，TaskWaker将id放入queue中，
-    /// executor自然会执行对应的task，即实现了task的唤醒操作。
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+/// 实现了Wake Trait，Task才可被唤醒；
+/// 唤醒操作即是将task放回其上一次执行的CPU的run queue（已经在run queue中时不重复放入）。
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            enqueue(self.cpu.load(Ordering::Relaxed), self);
+        }
     }
 
-    /// 唤醒task，即将id放入queue中；
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            enqueue(self.cpu.load(Ordering::Relaxed), self.clone());
+        }
     }
 }
 
-/// 实现了Wake Trait，能才可唤醒Executor中的task；
-/// 唤醒操作即是TaksWaker::wake_task()。
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
+
+
+/// 多个task在不同CPU上执行（或被窃取），被唤醒后都能执行完毕
+#[test_case]
+fn test_executor() {
+    use core::{future::Future, pin::Pin, sync::atomic::AtomicUsize, task::Poll};
+
+    /// 第一次poll时唤醒自己并返回Pending
+    struct YieldNow(bool);
+    impl Future for YieldNow {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
     }
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    const TASKS: usize = 16;
+    let mut executor = Executor::new();
+    for _ in 0 .. TASKS {
+        executor.spawn(Task::new(async {
+            YieldNow(false).await;
+            DONE.fetch_add(1, Ordering::SeqCst);
+        }));
     }
+    executor.run_until(|| DONE.load(Ordering::SeqCst) == TASKS);
 }
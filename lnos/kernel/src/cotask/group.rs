@@ -0,0 +1,144 @@
+//! task组
+//!
+//! TaskGroup持有一组子task的JoinHandle：
+//! - join_all等待所有子task结束，按创建顺序返回结果；
+//! - abort_all取消所有还没有结束的子task；
+//! - drop TaskGroup时取消所有还没有结束的子task（不会detach），
+//!   所以子task不会比TaskGroup活得更久（除非已经在执行中，本次poll返回后才会释放）。
+
+use alloc::vec::Vec;
+use core::future::Future;
+use super::join::{spawn, AbortHandle, JoinError, JoinHandle};
+
+
+/// 一组Output相同的子task
+pub struct TaskGroup<T> {
+    handles: Vec<JoinHandle<T>>,
+}
+
+impl<T> TaskGroup<T> {
+    pub const fn new() -> Self {
+        TaskGroup { handles: Vec::new() }
+    }
+
+    /// 还没有被join_all取走结果的子task数量
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// 取消所有还没有结束的子task（结果为JoinError::Cancelled）
+    pub fn abort_all(&self) {
+        for handle in self.handles.iter() {
+            handle.abort();
+        }
+    }
+}
+
+impl<T: Send + 'static> TaskGroup<T> {
+    /// 创建子task，返回可以单独取消该task的AbortHandle
+    pub fn spawn<F>(&mut self, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let handle = spawn(future);
+        let abort = handle.abort_handle();
+        self.handles.push(handle);
+        abort
+    }
+
+    /// 等待所有子task结束，按创建顺序返回结果
+    ///
+    /// 子task的结果取走后才从组中移除，所以join_all被drop时，剩下的子task仍会随TaskGroup被取消。
+    pub async fn join_all(&mut self) -> Vec<Result<T, JoinError>> {
+        let mut results = Vec::with_capacity(self.handles.len());
+        while let Some(handle) = self.handles.first_mut() {
+            let result = handle.await;
+            self.handles.remove(0);
+            results.push(result);
+        }
+        results
+    }
+}
+
+impl<T> Default for TaskGroup<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for TaskGroup<T> {
+    fn drop(&mut self) {
+        self.abort_all();
+    }
+}
+
+
+
+/// join_all按创建顺序返回所有子task的结果
+#[test_case]
+fn test_join_all() {
+    let mut executor = super::executor::Executor::new();
+    let mut group = TaskGroup::new();
+    for k in 0 .. 4 {
+        group.spawn(async move { k * 2 });
+    }
+    assert_eq!(group.len(), 4);
+    let results = executor.block_on(group.join_all());
+    assert_eq!(results, [Ok(0), Ok(2), Ok(4), Ok(6)]);
+    assert!(group.is_empty());
+}
+
+/// drop TaskGroup时取消所有没有结束的子task，并释放其Future
+#[test_case]
+fn test_drop_cancels() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut executor = super::executor::Executor::new();
+    let mut group = TaskGroup::new();
+    for _ in 0 .. 3 {
+        group.spawn(async {
+            let _guard = Guard;
+            core::future::pending::<()>().await;
+        });
+    }
+    let single = group.spawn(async {
+        let _guard = Guard;
+        core::future::pending::<()>().await;
+    });
+    // 单独取消一个，其余随TaskGroup取消
+    single.abort();
+    executor.run_ready_tasks();
+    drop(group);
+    executor.run_until(|| DROPPED.load(Ordering::SeqCst) == 4);
+}
+
+/// 子task panic只终止该task，JoinHandle得到JoinError::Panicked（开中断时才会捕获panic）
+#[test_case]
+fn test_panic_isolation() {
+    use x86_64::instructions::interrupts;
+
+    let mut executor = super::executor::Executor::new();
+    let mut group = TaskGroup::new();
+    group.spawn(async { 1 });
+    group.spawn(async {
+        let v: Option<usize> = None;
+        v.expect("panic in task (expected)")
+    });
+    group.spawn(async { 3 });
+    interrupts::enable();
+    let results = executor.block_on(group.join_all());
+    interrupts::disable();
+    assert_eq!(results, [Ok(1), Err(JoinError::Panicked), Ok(3)]);
+}
@@ -0,0 +1,194 @@
+//! task的创建和等待
+//!
+//! spawn可以在任意task中（以及中断处理函数中）创建task，返回等待其结果的JoinHandle：
+//! - Future的Output可以是任意类型，spawn将其包装成Output为()的Task，
+//!   执行完毕后将结果保存在与JoinHandle共享的JoinState中，并唤醒等待JoinHandle的task；
+//! - 新的task只放入run queue，不会在spawn中执行，所以在中断中调用时，task会推迟到Executor中执行；
+//! - drop JoinHandle不会影响task的执行（detach）；
+//! - abort取消task：task下一次被执行时释放其Future，JoinHandle返回JoinError::Cancelled；
+//! - task panic时只终止该task（见executor::begin_task_panic），JoinHandle返回JoinError::Panicked。
+
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use super::executor;
+use super::task::{Task, TaskId};
+
+
+/// task没有正常执行完毕的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// task被abort
+    Cancelled,
+    /// task执行时panic
+    Panicked,
+}
+
+
+/// task与JoinHandle共享的状态
+///
+/// - output: task的结果，由JoinHandle取走
+/// - finished: task已经执行完毕（output被取走后仍为true）
+/// - waker: 等待JoinHandle的task的waker
+struct JoinState<T> {
+    output: Option<Result<T, JoinError>>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+/// 等待task执行完毕并获取其结果的Future
+pub struct JoinHandle<T> {
+    id: TaskId,
+    task: Arc<Task>,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// task是否已经执行完毕（包括被取消和panic）
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+
+    /// 取消task，见AbortHandle::abort
+    pub fn abort(&self) {
+        abort_task(&self.task);
+    }
+
+    /// 获取可以取消task的AbortHandle（不能等待结果）
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle { task: self.task.clone() }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(output) = state.output.take() {
+            return Poll::Ready(output);
+        }
+        assert!(!state.finished, "JoinHandle polled after completion");
+        match state.waker.as_ref() {
+            Some(waker) if waker.will_wake(cx.waker()) => {},
+            _ => state.waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
+
+/// 取消task的句柄，可以复制
+#[derive(Clone)]
+pub struct AbortHandle {
+    task: Arc<Task>,
+}
+
+impl AbortHandle {
+    pub fn id(&self) -> TaskId {
+        self.task.id()
+    }
+
+    /// 取消task：task下一次被执行时释放其Future（正在poll时等待本次poll返回）
+    ///
+    /// task已经执行完毕时不做任何处理。
+    pub fn abort(&self) {
+        abort_task(&self.task);
+    }
+}
+
+/// 标记task被取消，并唤醒task，由Executor释放其Future
+fn abort_task(task: &Arc<Task>) {
+    task.abort();
+    task.wake_by_ref();
+}
+
+/// 全局的spawner，可以复制到任意task中使用
+///
+/// 所有CPU的Executor共享task队列，所以spawner不属于某个Executor。
+#[derive(Debug, Clone, Copy)]
+pub struct Spawner(());
+
+impl Spawner {
+    /// 创建task，放入当前CPU的run queue，返回等待其结果的JoinHandle
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState {
+            output: None,
+            finished: false,
+            waker: None,
+        }));
+        let task_state = state.clone();
+        let task = Task::new(async move {
+            let output = future.await;
+            finish(&task_state, Ok(output));
+        });
+        let abandon_state = state.clone();
+        task.set_on_abandon(Box::new(move |error| finish(&abandon_state, Err(error))));
+        let task = Arc::new(task);
+        let id = task.id();
+        executor::spawn_task(task.clone());
+        JoinHandle { id, task, state }
+    }
+}
+
+/// 保存task的结果，并唤醒等待JoinHandle的task
+fn finish<T>(state: &Mutex<JoinState<T>>, output: Result<T, JoinError>) {
+    let waker = {
+        let mut state = state.lock();
+        state.output = Some(output);
+        state.finished = true;
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// 获取全局的spawner
+pub fn spawner() -> Spawner {
+    Spawner(())
+}
+
+/// 创建task，等同于spawner().spawn(future)
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawner().spawn(future)
+}
+
+
+
+/// task中可以创建子task，并通过JoinHandle获取其结果
+#[test_case]
+fn test_spawn_join() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use alloc::{string::String, vec::Vec};
+
+    static RESULT: AtomicUsize = AtomicUsize::new(0);
+    let mut executor = executor::Executor::new();
+    let handle = spawn(async {
+        let children: Vec<_> = (1 ..= 4).map(|k| spawn(async move { k * 10 })).collect();
+        let mut sum = 0;
+        for child in children {
+            sum += child.await.unwrap();
+        }
+        let name = spawner().spawn(async { String::from("child") }).await.unwrap();
+        RESULT.store(sum + name.len(), Ordering::SeqCst);
+        sum
+    });
+    assert_eq!(executor.block_on(handle), Ok(100));
+    assert_eq!(RESULT.load(Ordering::SeqCst), 100 + 5);
+}
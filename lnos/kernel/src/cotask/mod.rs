@@ -1,15 +1,25 @@
 //! 协作式多任务处理
+//!
+//! 每个CPU运行一个Executor：BSP在启动线程（main）中运行，AP在启动后的idle线程中运行；
+//! task可以在不同CPU的Executor之间迁移（见executor模块）。
 
 pub mod task;
 pub mod executor;
+pub mod join;
+pub mod group;
+pub mod timer;
 
-pub fn run() {
+pub use join::{spawn, spawner, AbortHandle, JoinError, JoinHandle, Spawner};
+pub use group::TaskGroup;
+
+/// 运行BSP的Executor，main为第一个task（可以在其中创建其它task）
+pub fn run(main: impl core::future::Future<Output = ()> + Send + 'static) -> ! {
     let mut executor = executor::Executor::new();
-    executor.spawn(task::Task::new(first_task()));
-    executor.spawn(task::Task::new(super::driver::keyboard::task_keyboard()));
+    spawn(main);
     executor.run();
 }
 
-async fn first_task() {
-    println!("start task schedule");
+/// AP的Executor（在AP上调用），执行被唤醒到该CPU或从其它CPU窃取的task
+pub fn run_ap() -> ! {
+    executor::Executor::new().run();
 }
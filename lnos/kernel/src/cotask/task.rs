@@ -2,9 +2,11 @@ use core::{
     future::Future,
     pin::Pin,
     task::{Context, Poll},
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 use alloc::boxed::Box;
+use spin::Mutex;
+use super::join::JoinError;
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -26,23 +28,110 @@ impl TaskId {
 /// Output=(): 一个task不需要返回任何结果
 /// dyn Future: task可以使用何任实现Future<Output=()>的类型
 /// Pin: 禁止移动内存，禁止使用可变引用(&mut)
+/// Send: task可以在不同CPU的Executor之间迁移（被唤醒到其它CPU或被窃取）
+///
+/// - 调度状态：
+/// scheduled: task已经在某个run queue中，等待执行（防止被多次唤醒时重复放入）
+/// cpu: 上一次执行task的CPU，唤醒时放回该CPU的run queue
+/// repoll: poll期间task在其它CPU上被执行（获取Future的锁失败），本次poll结束后需要重新唤醒
+///
+/// - 结束状态（task没有执行完毕就结束时，通过on_abandon通知JoinHandle）：
+/// aborted: task已经被abort，Executor不再poll，而是释放Future
+/// panicked: poll时panic，Future处于不一致的状态，不再释放（泄露）
 pub struct Task {
     /// Task::id对cotask模块可见
     pub(super) id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    pub(super) scheduled: AtomicBool,
+    pub(super) cpu: AtomicUsize,
+    pub(super) repoll: AtomicBool,
+    aborted: AtomicBool,
+    panicked: AtomicBool,
+    on_abandon: Mutex<Option<Box<dyn FnOnce(JoinError) + Send>>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
-            future: Box::pin(future),
+            future: Mutex::new(Some(Box::pin(future))),
+            scheduled: AtomicBool::new(false),
+            cpu: AtomicUsize::new(0),
+            repoll: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            panicked: AtomicBool::new(false),
+            on_abandon: Mutex::new(None),
         }
     }
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
     /// 用于Executor对Task执行poll操作（本质是对Future执行poll操作）；
     /// Task::poll对cotask模块可见。
-    pub(super) fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+    ///
+    /// 执行完毕后立即释放Future（Task本身由Waker持有，可能在之后才释放）；
+    /// task正在其它CPU上执行时返回None。
+    pub(super) fn poll(&self, context: &mut Context) -> Option<Poll<()>> {
+        let mut future = self.future.try_lock()?;
+        let poll = match future.as_mut() {
+            Some(f) => f.as_mut().poll(context),
+            None => return Some(Poll::Ready(())),
+        };
+        if poll.is_ready() {
+            *future = None;
+        }
+        Some(poll)
+    }
+
+    /// 设置task没有执行完毕就结束时的回调
+    pub(super) fn set_on_abandon(&self, f: Box<dyn FnOnce(JoinError) + Send>) {
+        *self.on_abandon.lock() = Some(f);
+    }
+
+    fn abandon(&self, error: JoinError) {
+        let on_abandon = self.on_abandon.lock().take();
+        if let Some(f) = on_abandon {
+            f(error);
+        }
+    }
+
+    /// 标记task为aborted，由Executor在下一次执行时释放Future（调用者需要唤醒task）
+    pub(super) fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    pub(super) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// 释放被abort的task的Future（drop其中的所有值），task正在其它CPU上执行时返回None
+    pub(super) fn cancel(&self) -> Option<()> {
+        let future = self.future.try_lock()?.take();
+        if future.is_some() {
+            drop(future);
+            self.abandon(JoinError::Cancelled);
+        }
+        Some(())
+    }
+
+    /// poll时panic：Future的锁仍被丢弃的栈帧持有，不再执行
+    pub(super) fn set_panicked(&self) {
+        self.panicked.store(true, Ordering::SeqCst);
+        self.abandon(JoinError::Panicked);
+    }
+
+    pub(super) fn is_panicked(&self) -> bool {
+        self.panicked.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if *self.panicked.get_mut() {
+            // panic时Future可能处于不一致的状态，不能drop
+            core::mem::forget(self.future.get_mut().take());
+        }
     }
 }
@@ -0,0 +1,225 @@
+//! 定时器模块
+//!
+//! 使用时间轮（timer wheel）管理等待时间的task：
+//! - 时间轮有WHEEL_SIZE个slot，到期tick为deadline的定时器放在slot[deadline % WHEEL_SIZE]中；
+//! - 每次Timer中断只检查当前tick对应的slot，唤醒其中已经到期的定时器（未到期的属于之后的轮次）；
+//! - 定时器通过task的Waker唤醒，即由executor重新poll对应的task。
+//!
+//! task可以通过sleep等待一段时间，或通过Interval周期性地执行。
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::time;
+
+
+/// 时间轮的slot数量
+const WHEEL_SIZE: usize = 256;
+
+/// 等待到期的定时器
+struct Timer {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+/// 时间轮只能在关中断时访问（Timer中断中也会访问）
+static WHEEL: Mutex<[Vec<Timer>; WHEEL_SIZE]> = {
+    const EMPTY: Vec<Timer> = Vec::new();
+    Mutex::new([EMPTY; WHEEL_SIZE])
+};
+
+fn slot(deadline: u64) -> usize {
+    (deadline % WHEEL_SIZE as u64) as usize
+}
+
+/// 注册（或更新）定时器，若已经到期则返回false
+fn register(id: u64, deadline: u64, waker: &Waker) -> bool {
+    interrupts::without_interrupts(|| {
+        // 持有锁后再检查：Timer中断（可能在其它CPU上）先增加tick再获取锁检查slot，
+        // 所以持有锁时tick还没有到deadline，说明该slot还没有被检查，不会错过
+        let mut wheel = WHEEL.lock();
+        if time::ticks() >= deadline {
+            return false;
+        }
+        let timers = &mut wheel[slot(deadline)];
+        match timers.iter_mut().find(|t| t.id == id) {
+            Some(timer) if timer.waker.will_wake(waker) => {},
+            Some(timer) => timer.waker = waker.clone(),
+            None => timers.push(Timer { id, deadline, waker: waker.clone() }),
+        }
+        true
+    })
+}
+
+/// 取消定时器
+fn cancel(id: u64, deadline: u64) {
+    interrupts::without_interrupts(|| WHEEL.lock()[slot(deadline)].retain(|t| t.id != id));
+}
+
+/// Timer中断调用：唤醒在now到期的定时器
+pub(crate) fn expire(now: u64) {
+    let mut wheel = WHEEL.lock();
+    wheel[slot(now)].retain(|t| {
+        if t.deadline <= now {
+            t.waker.wake_by_ref();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// 等待到指定tick的Future
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    /// 到期的tick
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// 修改到期的tick
+    fn reset(&mut self, deadline: u64) {
+        if self.registered {
+            cancel(self.id, self.deadline);
+            self.registered = false;
+        }
+        self.deadline = deadline;
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.deadline || !register(self.id, self.deadline, cx.waker()) {
+            self.registered = false;
+            return Poll::Ready(());
+        }
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            cancel(self.id, self.deadline);
+        }
+    }
+}
+
+/// 等待到tick为deadline
+pub fn sleep_until(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        deadline,
+        registered: false,
+    }
+}
+
+/// 等待duration（精度为一个tick）
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::ticks() + time::duration_to_ticks(duration))
+}
+
+/// 周期性的定时器，每个周期产生一次到期的tick
+///
+/// 按固定的频率触发：错过的周期会立即依次产生，不会累积误差。
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+/// 创建周期为period（至少一个tick）的Interval，第一个周期在period后到期
+pub fn interval(period: Duration) -> Interval {
+    let period = time::duration_to_ticks(period).max(1);
+    Interval {
+        period,
+        sleep: sleep_until(time::ticks() + period),
+    }
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        time::ticks_to_duration(self.period)
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let fired = self.sleep.deadline();
+                let period = self.period;
+                self.sleep.reset(fired + period);
+                Poll::Ready(Some(fired))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+
+
+/// 定时器由Timer中断唤醒
+#[test_case]
+fn test_sleep() {
+    use alloc::{sync::Arc, task::Wake};
+    use core::sync::atomic::AtomicBool;
+
+    struct FlagWaker(AtomicBool);
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // 在内核线程中执行（已经开中断）
+    crate::kthread::spawn("timer_test", || {
+        let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let start = time::ticks();
+        let mut sleep = sleep(Duration::from_millis(30));
+        assert!(sleep.deadline() >= start + 3);
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+        while !flag.0.load(Ordering::SeqCst) {
+            x86_64::instructions::hlt();
+        }
+        assert!(time::ticks() >= start + 3);
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready());
+
+        let mut interval = interval(Duration::from_millis(20));
+        let first = interval.sleep.deadline();
+        for k in 0 .. 3 {
+            flag.0.store(false, Ordering::SeqCst);
+            loop {
+                if let Poll::Ready(Some(tick)) = Pin::new(&mut interval).poll_next(&mut cx) {
+                    assert_eq!(tick, first + k * 2);
+                    break;
+                }
+                while !flag.0.load(Ordering::SeqCst) {
+                    x86_64::instructions::hlt();
+                }
+            }
+        }
+    }).join();
+}
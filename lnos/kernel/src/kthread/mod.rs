@@ -0,0 +1,168 @@
+//! 抢占式内核线程
+//!
+//! 每个内核线程有独立的内核栈和上下文，由timer中断驱动轮转调度；
+//! 即使线程一直占用CPU（不主动让出），也会在时间片用完后被抢占。
+//! 所有CPU共享同一个就绪队列，线程可以在不同的CPU上执行。
+//!
+//! cotask的Executor运行在BSP的启动线程（main）和AP的idle线程中，可以与其它内核线程并发执行。
+
+pub mod thread;
+mod scheduler;
+
+use alloc::{boxed::Box, sync::Arc};
+use core::marker::PhantomData;
+use thread::Thread;
+use crate::arch::addr_space::AddressSpace;
+pub use thread::{JoinHandle, ThreadId, ThreadState};
+
+
+/// 初始化内核线程
+///
+/// 将当前的执行流作为启动线程（main），并创建idle线程；
+/// 需要在使能中断之前调用。
+pub fn init() {
+    let boot = Thread::boot("main");
+    let idle = Thread::new("idle", Box::new(|| {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }));
+    scheduler::init(boot, idle);
+}
+
+/// 初始化AP上的内核线程（在AP上调用）
+///
+/// 将AP当前的执行流作为该CPU的idle线程；之后AP的Local APIC定时器会从就绪队列中调度线程。
+pub fn init_ap() {
+    let name = alloc::format!("idle/{}", crate::arch::cpu::id());
+    scheduler::init_ap(Thread::boot(&name));
+}
+
+/// 创建一个内核线程，并添加到就绪队列
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let thread = Thread::new(name, Box::new(move || {
+        let value = f();
+        exit_with(value);
+    }));
+    scheduler::add(thread.clone());
+    JoinHandle {
+        thread,
+        _marker: PhantomData,
+    }
+}
+
+/// 当前线程
+pub fn current() -> Arc<Thread> {
+    scheduler::current()
+}
+
+/// 让出CPU，切换到下一个就绪线程
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// 当前线程睡眠指定的tick数
+pub fn sleep(ticks: u64) {
+    scheduler::sleep_until(scheduler::ticks() + ticks);
+}
+
+/// 退出当前线程（没有返回值，JoinHandle::join返回None）
+pub fn exit() -> ! {
+    scheduler::exit();
+}
+
+/// 退出当前线程，并设置返回值
+///
+/// value需要与JoinHandle<T>中的T类型相同，否则join时无法获取返回值。
+pub fn exit_with<T: Send + 'static>(value: T) -> ! {
+    let value: Box<dyn core::any::Any + Send> = Box::new(value);
+    // timer中断中也会对inner加锁，需要关中断，否则被抢占时会死锁
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        current().inner.lock().result = Some(value);
+    });
+    scheduler::exit();
+}
+
+/// 设置当前线程的地址空间，并立即切换；None表示切换回内核的地址空间
+pub fn set_address_space(space: Option<Arc<AddressSpace>>) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match space.as_ref() {
+            Some(space) => space.activate(),
+            None => crate::arch::addr_space::activate_kernel(),
+        }
+        // 旧的地址空间在这里释放，此时已经不再使用
+        let old = core::mem::replace(&mut current().inner.lock().space, space);
+        drop(old);
+    });
+}
+
+/// 启动以来的tick数
+pub fn ticks() -> u64 {
+    scheduler::ticks()
+}
+
+/// 时钟中断调用：更新tick并检查时间片
+///
+/// 内核线程未初始化时不做任何处理。
+pub(crate) fn tick() {
+    scheduler::tick();
+}
+
+/// 其它CPU的本地定时器中断调用：检查当前线程的时间片
+pub(crate) fn tick_local() {
+    if scheduler::is_initialized() {
+        scheduler::tick_local();
+    }
+}
+
+/// 中断返回前调用：时间片用完时切换线程
+///
+/// 需要在通知EOI之后调用，否则切换到其它线程后，无法再响应timer中断。
+pub(crate) fn preempt() {
+    if scheduler::is_initialized() {
+        scheduler::preempt();
+    }
+}
+
+
+
+#[test_case]
+fn test_kthread_join() {
+    use alloc::vec::Vec;
+
+    let handles: Vec<_> = (0 .. 3)
+        .map(|k| spawn("test", move || {
+            yield_now();
+            k * 2
+        }))
+        .collect();
+    for (k, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Some(k * 2));
+    }
+}
+
+#[test_case]
+fn test_kthread_yield() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let handle = spawn("test", || {
+        for _ in 0 .. 10 {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+    });
+    assert_eq!(handle.join(), Some(()));
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 10);
+}
+
+/// 通过exit退出的线程没有返回值
+#[test_case]
+fn test_kthread_exit() {
+    let handle: JoinHandle<usize> = spawn("test", || exit());
+    assert_eq!(handle.join(), None);
+}
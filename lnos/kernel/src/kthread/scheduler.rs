@@ -0,0 +1,338 @@
+use super::thread::{Thread, ThreadState};
+use alloc::{
+    collections::VecDeque,
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::{instructions::interrupts, VirtAddr};
+use crate::arch::context::Context;
+use crate::arch::cpu::{self, MAX_CPUS};
+
+
+/// 每个线程的时间片（tick数）
+pub const TIME_SLICE: u64 = 2;
+
+/// 每个CPU的调度状态
+///
+/// - current: 当前正在该CPU上执行的线程
+/// - prev: 刚被切换出去的线程，由下一个线程在finish_switch中处理
+/// - requeue: prev切换出去时仍然可以执行（被抢占或让出），需要放回就绪队列
+/// - idle: 没有其它就绪线程时执行的线程（每个CPU各有一个）
+struct CpuState {
+    current: Option<Arc<Thread>>,
+    prev: Option<Arc<Thread>>,
+    requeue: bool,
+    idle: Option<Arc<Thread>>,
+    slice: u64,
+    need_resched: bool,
+}
+
+impl CpuState {
+    const fn new() -> Self {
+        CpuState {
+            current: None,
+            prev: None,
+            requeue: false,
+            idle: None,
+            slice: TIME_SLICE,
+            need_resched: false,
+        }
+    }
+
+    fn is_idle(&self, thread: &Arc<Thread>) -> bool {
+        self.idle.as_ref().map_or(false, |idle| Arc::ptr_eq(idle, thread))
+    }
+
+    fn current(&self) -> &Arc<Thread> {
+        self.current.as_ref().expect("kthread not initialized")
+    }
+}
+
+/// 轮转（Round-Robin）调度器
+///
+/// - ready: 就绪队列，所有CPU共享，按先进先出的顺序调度
+/// - sleeping: 睡眠中的线程，由tick唤醒
+/// - cpus: 每个CPU的调度状态，下标为CPU编号
+///
+/// SCHEDULER只能在关中断时访问（timer中断中也会访问）。
+///
+/// 多个CPU时，线程被唤醒（放入就绪队列）时可能还没有在原来的CPU上完成切换，
+/// 所以schedule跳过Thread::on_cpu为true的线程，留在就绪队列中，等其切换完成（上下文已保存）后再调度；
+/// on_cpu只在持有SCHEDULER时修改，不会有CPU在关中断时等待其它CPU完成切换。
+struct Scheduler {
+    ready: VecDeque<Arc<Thread>>,
+    sleeping: Vec<Arc<Thread>>,
+    cpus: [CpuState; MAX_CPUS],
+    ticks: u64,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+        ready: VecDeque::new(),
+        sleeping: Vec::new(),
+        cpus: {
+            const NEW: CpuState = CpuState::new();
+            [NEW; MAX_CPUS]
+        },
+        ticks: 0,
+    });
+}
+
+impl Scheduler {
+    /// 当前CPU的调度状态
+    fn this_cpu(&mut self) -> &mut CpuState {
+        &mut self.cpus[cpu::id()]
+    }
+
+    fn current(&mut self) -> &Arc<Thread> {
+        self.this_cpu().current()
+    }
+
+    /// 检查当前CPU上线程的时间片
+    fn check_slice(&mut self) {
+        let has_ready = !self.ready.is_empty();
+        let state = self.this_cpu();
+        let idle = match state.current.as_ref() {
+            Some(cur) => state.is_idle(cur),
+            None => return,
+        };
+        state.slice = state.slice.saturating_sub(1);
+        if (state.slice == 0 || idle) && has_ready {
+            state.need_resched = true;
+        }
+    }
+}
+
+/// 初始化BSP的调度状态
+pub(super) fn init(boot: Arc<Thread>, idle: Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        cpu::this_cpu().set_current_thread(boot.id().as_u64());
+        let state = sched.this_cpu();
+        state.current = Some(boot);
+        state.idle = Some(idle);
+    });
+}
+
+/// 初始化AP的调度状态：AP的启动执行流即为该CPU的idle线程
+pub(super) fn init_ap(idle: Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        cpu::this_cpu().set_current_thread(idle.id().as_u64());
+        let state = sched.this_cpu();
+        state.current = Some(idle.clone());
+        state.idle = Some(idle);
+    });
+}
+
+pub(super) fn is_initialized() -> bool {
+    interrupts::without_interrupts(|| SCHEDULER.lock().this_cpu().current.is_some())
+}
+
+pub(super) fn current() -> Arc<Thread> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().current().clone())
+}
+
+pub(super) fn ticks() -> u64 {
+    interrupts::without_interrupts(|| SCHEDULER.lock().ticks)
+}
+
+/// 添加线程到就绪队列
+pub(super) fn add(thread: Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        thread.set_state(ThreadState::Ready);
+        SCHEDULER.lock().ready.push_back(thread);
+    });
+}
+
+/// 让出CPU
+pub(super) fn yield_now() {
+    interrupts::without_interrupts(|| schedule());
+}
+
+/// 睡眠到指定的tick
+pub(super) fn sleep_until(tick: u64) {
+    interrupts::without_interrupts(|| {
+        {
+            let mut sched = SCHEDULER.lock();
+            if sched.ticks >= tick {
+                return;
+            }
+            let cur = sched.current().clone();
+            cur.inner.lock().wake_at = tick;
+            cur.set_state(ThreadState::Sleeping);
+            sched.sleeping.push(cur);
+        }
+        schedule();
+    });
+}
+
+/// 阻塞当前线程，直到thread退出
+pub(super) fn join(thread: &Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        {
+            let mut sched = SCHEDULER.lock();
+            let cur = sched.current();
+            assert!(!Arc::ptr_eq(cur, thread), "thread can not join itself");
+            // 与exit同样在inner的锁中检查和修改状态，保证不会错过唤醒
+            let mut inner = thread.inner.lock();
+            if thread.state() == ThreadState::Exited {
+                return;
+            }
+            inner.joiners.push(cur.clone());
+            cur.set_state(ThreadState::Blocked);
+        }
+        schedule();
+    });
+}
+
+/// 退出当前线程，并唤醒所有等待该线程的线程
+pub(super) fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut sched = SCHEDULER.lock();
+        let cur = sched.current().clone();
+        let joiners = {
+            let mut inner = cur.inner.lock();
+            cur.set_state(ThreadState::Exited);
+            core::mem::take(&mut inner.joiners)
+        };
+        for joiner in joiners {
+            joiner.set_state(ThreadState::Ready);
+            sched.ready.push_back(joiner);
+        }
+    }
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// 时钟中断处理（BSP的Timer中断）
+///
+/// 更新tick，唤醒到时的睡眠线程，并检查当前线程的时间片是否用完。
+pub(super) fn tick() {
+    let mut sched = SCHEDULER.lock();
+    if sched.this_cpu().current.is_none() {
+        return;
+    }
+    sched.ticks += 1;
+
+    let now = sched.ticks;
+    let mut k = 0;
+    while k < sched.sleeping.len() {
+        if sched.sleeping[k].inner.lock().wake_at <= now {
+            let thread = sched.sleeping.swap_remove(k);
+            thread.set_state(ThreadState::Ready);
+            sched.ready.push_back(thread);
+        } else {
+            k += 1;
+        }
+    }
+
+    sched.check_slice();
+}
+
+/// 其它CPU的本地定时器中断处理：只检查当前线程的时间片
+pub(super) fn tick_local() {
+    SCHEDULER.lock().check_slice();
+}
+
+/// 在中断返回前检查是否需要抢占当前线程
+pub(super) fn preempt() {
+    let need_resched = {
+        let mut sched = SCHEDULER.lock();
+        let state = sched.this_cpu();
+        state.current.is_some() && state.need_resched
+    };
+    if need_resched {
+        schedule();
+    }
+}
+
+/// 切换到下一个就绪线程
+///
+/// 需要在关中断时调用；若当前线程仍处于Running状态，则在切换后重新放入就绪队列。
+fn schedule() {
+    let (from, next) = {
+        let mut sched = SCHEDULER.lock();
+        let cur = sched.this_cpu().current.take().expect("kthread not initialized");
+        // 跳过还没有在其它CPU上完成切换的线程（当前线程自己除外）
+        let next = sched.ready.iter()
+            .position(|t| Arc::ptr_eq(t, &cur) || !t.on_cpu.load(Ordering::Acquire))
+            .and_then(|k| sched.ready.remove(k));
+        let state = sched.this_cpu();
+        state.need_resched = false;
+        state.slice = TIME_SLICE;
+
+        let runnable = cur.state() == ThreadState::Running;
+        let next = match next {
+            Some(next) if Arc::ptr_eq(&next, &cur) => {
+                // 当前线程在切换之前已经被唤醒（如等待的线程在其它CPU上退出），继续执行
+                cur.set_state(ThreadState::Running);
+                state.current = Some(cur);
+                return;
+            },
+            Some(next) => next,
+            None if runnable => {
+                // 没有其它就绪线程，继续执行当前线程
+                state.current = Some(cur);
+                return;
+            },
+            None => state.idle.clone().expect("kthread not initialized"),
+        };
+
+        if runnable {
+            cur.set_state(ThreadState::Ready);
+        }
+        next.set_state(ThreadState::Running);
+        if let Some((_, stack_top)) = next.stack_range() {
+            // 用户态的线程进入内核时，使用该线程的内核栈
+            crate::arch::gdt::set_kernel_stack(VirtAddr::new(stack_top as u64));
+        }
+        match next.inner.lock().space.as_ref() {
+            Some(space) => space.activate(),
+            None => crate::arch::addr_space::activate_kernel(),
+        }
+        cpu::this_cpu().set_current_thread(next.id().as_u64());
+        state.requeue = runnable && !state.is_idle(&cur);
+        next.on_cpu.store(true, Ordering::Relaxed);
+        let from = cur.context();
+        state.prev = Some(cur);
+        state.current = Some(next.clone());
+        (from, next)
+    };
+
+    let to = next.context() as *const Context;
+    drop(next);
+
+    // prev和current持有线程的Arc，保证切换期间from和to有效
+    unsafe { Context::switch(from, to) };
+    finish_switch();
+}
+
+/// 完成线程切换的收尾工作
+///
+/// 在切换到的线程上执行：prev的上下文已经保存，清除其on_cpu，使其可以在其它CPU上执行；
+/// 将仍然可以执行的prev线程放回就绪队列；已退出的线程在这里释放（此时已经不在它的栈上执行了）。
+pub(super) fn finish_switch() {
+    let prev = {
+        let mut sched = SCHEDULER.lock();
+        let state = sched.this_cpu();
+        let requeue = core::mem::take(&mut state.requeue);
+        match state.prev.take() {
+            Some(prev) => {
+                prev.on_cpu.store(false, Ordering::Release);
+                if requeue {
+                    sched.ready.push_back(prev);
+                    None
+                } else {
+                    Some(prev)
+                }
+            },
+            None => None,
+        }
+    };
+    drop(prev);
+}
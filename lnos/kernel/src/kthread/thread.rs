@@ -0,0 +1,204 @@
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    cell::UnsafeCell,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::arch::context::Context;
+use crate::arch::addr_space::AddressSpace;
+use crate::arch::kstack::KernelStack;
+
+
+/// 内核线程栈大小（与AP的启动栈相同，Exception的报告和调用栈也在该栈上打印）
+pub const KSTACK_SIZE: usize = 0x1000 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+/// ThreadId作为Thread唯一id，生成方式同TaskId
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// 线程状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    /// 在就绪队列中等待调度
+    Ready,
+    /// 正在CPU上执行
+    Running,
+    /// 在睡眠队列中等待时间到达
+    Sleeping,
+    /// 等待其它线程退出（join）
+    Blocked,
+    /// 已经退出
+    Exited,
+}
+
+impl ThreadState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Sleeping,
+            3 => ThreadState::Blocked,
+            _ => ThreadState::Exited,
+        }
+    }
+}
+
+/// 线程中需要加锁访问的数据
+///
+/// timer中断中也会访问（唤醒睡眠线程、切换地址空间），所以只能在关中断时加锁。
+pub(super) struct ThreadInner {
+    /// 睡眠时被唤醒的tick
+    pub(super) wake_at: u64,
+    /// 等待该线程退出的线程
+    pub(super) joiners: Vec<Arc<Thread>>,
+    /// 线程退出时的返回值
+    pub(super) result: Option<Box<dyn Any + Send>>,
+    /// 线程使用的地址空间，None表示内核的地址空间
+    pub(super) space: Option<Arc<AddressSpace>>,
+}
+
+/// 内核线程
+///
+/// 每个线程有独立的内核栈（带guard page，见arch::kstack）和上下文；
+/// 启动线程（boot）直接使用bootloader设置的栈（AP的idle线程使用smp模块分配的启动栈），所以stack为None。
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    /// 线程上下文只在关中断时由调度器访问
+    context: UnsafeCell<Context>,
+    stack: Option<KernelStack>,
+    /// 是否正在某个CPU上执行（包括正在切换出去，上下文还没有保存完成）
+    pub(super) on_cpu: AtomicBool,
+    /// 线程状态（ThreadState），不需要加锁即可读取
+    state: AtomicU8,
+    pub(super) inner: Mutex<ThreadInner>,
+}
+
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    /// 创建一个新的线程，线程从main开始执行
+    pub(super) fn new(name: &str, main: Box<dyn FnOnce() + Send>) -> Arc<Thread> {
+        let stack = KernelStack::new(KSTACK_SIZE, name).expect("failed to allocate kernel stack");
+        let stack_top = stack.top().as_u64() as usize;
+        // main是胖指针，需要再Box一次才能通过usize传递
+        let arg = Box::into_raw(Box::new(main)) as usize;
+        Arc::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            context: UnsafeCell::new(Context::new(stack_top, thread_entry, arg)),
+            stack: Some(stack),
+            on_cpu: AtomicBool::new(false),
+            state: AtomicU8::new(ThreadState::Ready as u8),
+            inner: Mutex::new(ThreadInner {
+                wake_at: 0,
+                joiners: Vec::new(),
+                result: None,
+                space: None,
+            }),
+        })
+    }
+
+    /// 将当前的执行流包装成线程
+    pub(super) fn boot(name: &str) -> Arc<Thread> {
+        Arc::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            context: UnsafeCell::new(Context::default()),
+            stack: None,
+            on_cpu: AtomicBool::new(true),
+            state: AtomicU8::new(ThreadState::Running as u8),
+            inner: Mutex::new(ThreadInner {
+                wake_at: 0,
+                joiners: Vec::new(),
+                result: None,
+                space: None,
+            }),
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    pub(super) fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// 线程使用的地址空间
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        interrupts::without_interrupts(|| self.inner.lock().space.clone())
+    }
+
+    /// 内核栈的地址范围
+    pub fn stack_range(&self) -> Option<(usize, usize)> {
+        self.stack.as_ref().map(|s| (s.bottom().as_u64() as usize, s.top().as_u64() as usize))
+    }
+
+    pub(super) fn context(&self) -> *mut Context {
+        self.context.get()
+    }
+}
+
+/// 所有新线程的入口
+///
+/// 新线程第一次被调度时，从这里开始执行，需要先完成调度器的切换收尾，再使能中断。
+extern "C" fn thread_entry(arg: usize) -> ! {
+    super::scheduler::finish_switch();
+    interrupts::enable();
+
+    let main = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+    main();
+    super::exit();
+}
+
+/// 线程的句柄，用于等待线程退出并获取返回值
+pub struct JoinHandle<T> {
+    pub(super) thread: Arc<Thread>,
+    pub(super) _marker: PhantomData<T>,
+}
+
+impl<T: 'static> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// 阻塞当前线程，直到线程退出，并返回线程的返回值
+    ///
+    /// 线程通过exit退出（没有返回值）或返回值类型不是T时，返回None。
+    pub fn join(self) -> Option<T> {
+        super::scheduler::join(&self.thread);
+        let result = interrupts::without_interrupts(|| self.thread.inner.lock().result.take());
+        result
+            .and_then(|r| r.downcast::<T>().ok())
+            .map(|r| *r)
+    }
+}
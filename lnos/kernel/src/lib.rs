@@ -21,7 +21,11 @@ extern crate rlibc; // 需要使用rlibc中的memcpy、memset等函数
 #[macro_use]
 pub mod console;
 pub mod test;
+pub mod time;
 pub mod cotask;
+pub mod kthread;
+pub mod syscall;
+pub mod loader;
 pub mod driver;
 
 // 设置arch
@@ -33,5 +37,8 @@ pub mod arch;
 pub use arch::kernel_start;
 
 pub fn kernel_main() {
-    cotask::run();
+    cotask::run(async {
+        println!("start task schedule");
+        cotask::spawn(driver::keyboard::task_keyboard());
+    });
 }
@@ -0,0 +1,184 @@
+//! ELF64文件解析
+//!
+//! 只解析加载所需要的ELF Header和Program Header，
+//! 只支持x86_64、小端、静态链接的可执行文件（ET_EXEC）。
+
+use core::convert::TryInto;
+use core::ops::Range;
+
+
+/// Program Header类型
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+/// Program Header权限
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// ELF解析错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// 文件长度不足
+    TooShort,
+    /// 不是ELF文件
+    BadMagic,
+    /// 不是64位小端的ELF文件
+    BadClass,
+    /// 不是可执行文件
+    NotExecutable,
+    /// 不是x86_64的程序
+    BadMachine,
+    /// Program Header表超出文件范围
+    BadProgramHeader,
+    /// PT_LOAD段的数据超出文件范围，或地址不合法
+    BadSegment,
+    /// 程序入口不在可执行的PT_LOAD段中
+    BadEntry,
+}
+
+/// Program Header
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+/// ELF文件
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+fn read_u16(data: &[u8], ofs: usize) -> u16 {
+    u16::from_le_bytes(data[ofs .. ofs + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], ofs: usize) -> u32 {
+    u32::from_le_bytes(data[ofs .. ofs + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], ofs: usize) -> u64 {
+    u64::from_le_bytes(data[ofs .. ofs + 8].try_into().unwrap())
+}
+
+impl<'a> ElfFile<'a> {
+    /// 解析并校验ELF文件
+    ///
+    /// PT_LOAD段的虚拟地址需要位于user中，程序入口需要位于可执行（PF_X）的PT_LOAD段中。
+    pub fn parse(data: &'a [u8], user: Range<u64>) -> Result<Self, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0 .. 4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(ElfError::BadClass);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::BadMachine);
+        }
+
+        let elf = ElfFile {
+            data,
+            entry: read_u64(data, 24),
+            phoff: read_u64(data, 32) as usize,
+            phentsize: read_u16(data, 54) as usize,
+            phnum: read_u16(data, 56) as usize,
+        };
+        let phend = elf.phnum
+            .checked_mul(elf.phentsize)
+            .and_then(|len| len.checked_add(elf.phoff));
+        match phend {
+            Some(end) if end <= data.len() && elf.phentsize >= PHDR_SIZE => {},
+            _ => return Err(ElfError::BadProgramHeader),
+        }
+
+        for ph in elf.program_headers().filter(|ph| ph.p_type == PT_LOAD) {
+            let file_ok = ph.offset
+                .checked_add(ph.filesz)
+                .map_or(false, |end| end <= data.len() as u64);
+            let mem_ok = ph.vaddr
+                .checked_add(ph.memsz)
+                .map_or(false, |end| ph.vaddr >= user.start && end <= user.end);
+            if !file_ok || !mem_ok || ph.filesz > ph.memsz {
+                return Err(ElfError::BadSegment);
+            }
+        }
+
+        let entry_ok = elf.program_headers()
+            .filter(|ph| ph.p_type == PT_LOAD && ph.flags & PF_X != 0)
+            .any(|ph| (ph.vaddr .. ph.vaddr + ph.memsz).contains(&elf.entry));
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(elf)
+    }
+
+    /// 遍历所有Program Header
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let (phoff, phentsize) = (self.phoff, self.phentsize);
+        (0 .. self.phnum).map(move |k| {
+            let ofs = phoff + k * phentsize;
+            ProgramHeader {
+                p_type: read_u32(data, ofs),
+                flags: read_u32(data, ofs + 4),
+                offset: read_u64(data, ofs + 8),
+                vaddr: read_u64(data, ofs + 16),
+                filesz: read_u64(data, ofs + 32),
+                memsz: read_u64(data, ofs + 40),
+                align: read_u64(data, ofs + 48),
+            }
+        })
+    }
+
+    /// 段在文件中的数据
+    pub fn segment_data(&self, ph: &ProgramHeader) -> &'a [u8] {
+        &self.data[ph.offset as usize .. (ph.offset + ph.filesz) as usize]
+    }
+
+    /// Program Header表加载后的虚拟地址（用于auxv中的AT_PHDR）
+    ///
+    /// 优先使用PT_PHDR，否则查找包含Program Header表的PT_LOAD段。
+    pub fn phdr_addr(&self) -> Option<u64> {
+        let phoff = self.phoff as u64;
+        self.program_headers()
+            .find(|ph| ph.p_type == PT_PHDR)
+            .map(|ph| ph.vaddr)
+            .or_else(|| {
+                self.program_headers()
+                    .find(|ph| ph.p_type == PT_LOAD && ph.offset <= phoff && phoff < ph.offset + ph.filesz)
+                    .map(|ph| ph.vaddr + (phoff - ph.offset))
+            })
+    }
+
+    pub fn phnum(&self) -> usize {
+        self.phnum
+    }
+
+    pub fn phentsize(&self) -> usize {
+        self.phentsize
+    }
+}
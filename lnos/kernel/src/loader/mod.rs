@@ -0,0 +1,227 @@
+//! 用户程序加载模块
+//!
+//! 加载静态链接的ELF64可执行文件：
+//! - 创建新的AddressSpace，将PT_LOAD段按R/W/X权限映射到用户空间，.bss部分清零；
+//! - 在用户栈顶按System V ABI放置argc、argv、envp和auxv；
+//! - 在新的内核线程中切换到程序的AddressSpace，通过enter_user跳转到程序入口，在Ring 3中执行。
+//!
+//! 目前用户程序由include_bytes!嵌入到内核中（见PROGRAMS），程序的源码在lnos/user中。
+
+pub mod elf;
+
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use crate::arch::addr_space::{AddressSpace, MapError};
+use crate::arch::usermode::{enter_user, USER_END, USER_START};
+use crate::kthread::{self, JoinHandle};
+use elf::{ElfFile, ProgramHeader, PF_W, PF_X, PT_LOAD};
+pub use elf::ElfError;
+
+
+/// 用户栈的栈顶和大小
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const USER_STACK_SIZE: u64 = 0x1000 * 16;
+
+/// auxv的类型
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// 嵌入到内核中的用户程序
+pub static PROGRAMS: &[(&str, &[u8])] = &[
+    ("hello", include_bytes!("../../../user/bin/hello")),
+];
+
+/// 按名称查找嵌入的用户程序
+pub fn find_program(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS.iter().find(|(n, _)| *n == name).map(|(_, image)| *image)
+}
+
+/// 加载错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// ELF文件不合法
+    Elf(ElfError),
+    /// 映射用户内存失败
+    Map(MapError),
+    /// 没有可用的物理内存
+    OutOfMemory,
+    /// argv和envp超出了用户栈的大小
+    ArgsTooLong,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::OutOfMemory => LoadError::OutOfMemory,
+            err => LoadError::Map(err),
+        }
+    }
+}
+
+fn user_range() -> core::ops::Range<u64> {
+    USER_START as u64 .. USER_END as u64
+}
+
+/// 创建一个内核线程运行用户程序
+///
+/// 程序加载到新的AddressSpace中，加载失败直接返回错误；
+/// 程序的退出码（exit系统调用的参数）作为线程的返回值。
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<JoinHandle<i32>, LoadError> {
+    let elf = ElfFile::parse(image, user_range())?;
+    let mut space = AddressSpace::new().ok_or(LoadError::OutOfMemory)?;
+    let (entry, stack) = load(&mut space, &elf, argv, envp)?;
+    let space = Arc::new(space);
+    Ok(kthread::spawn(name, move || -> i32 {
+        kthread::set_address_space(Some(space));
+        enter_user(entry, stack)
+    }))
+}
+
+/// 将ELF文件加载到space中，返回程序入口和初始的用户栈
+pub fn load(space: &mut AddressSpace, elf: &ElfFile, argv: &[&str], envp: &[&str]) -> Result<(VirtAddr, VirtAddr), LoadError> {
+    for ph in elf.program_headers().filter(|ph| ph.p_type == PT_LOAD && ph.memsz > 0) {
+        load_segment(space, elf, &ph)?;
+    }
+    let entry = VirtAddr::try_new(elf.entry).map_err(|_| ElfError::BadEntry)?;
+    let stack = setup_stack(space, elf, argv, envp)?;
+    Ok((entry, stack))
+}
+
+/// 段权限转换成页表flags
+fn segment_flags(ph: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if ph.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if ph.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// 映射一个PT_LOAD段，并复制文件中的数据（超出filesz的部分保持为0）
+///
+/// 段需要位于用户空间（由ElfFile::parse检查），且不能与已经映射的page重叠
+/// （两个段共用一个Page时无法独立设置权限，见lnos/user/user.ld），否则返回MapError::AlreadyMapped；
+/// 失败时已经映射的Frame属于space，随space一起释放。
+fn load_segment(space: &mut AddressSpace, elf: &ElfFile, ph: &ProgramHeader) -> Result<(), LoadError> {
+    let start = ph.vaddr & !(Size4KiB::SIZE - 1);
+    let len = (ph.vaddr + ph.memsz - start) as usize;
+    space.map(VirtAddr::new(start), len, segment_flags(ph))?;
+    space.write(VirtAddr::new(ph.vaddr), elf.segment_data(ph))?;
+    Ok(())
+}
+
+/// 建立用户栈
+///
+/// 栈顶依次存放字符串和AT_RANDOM的16字节，之后是按16字节对齐的：
+/// argc | argv[0..argc] | NULL | envp[..] | NULL | auxv (type, value)... | AT_NULL
+fn setup_stack(space: &mut AddressSpace, elf: &ElfFile, argv: &[&str], envp: &[&str]) -> Result<VirtAddr, LoadError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    space.map(VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE), USER_STACK_SIZE as usize, flags)?;
+
+    let strings: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * 7;
+    if strings + 16 + (words as u64 + 2) * 8 > USER_STACK_SIZE {
+        return Err(LoadError::ArgsTooLong);
+    }
+
+    let mut sp = USER_STACK_TOP;
+    let mut push_bytes = |space: &mut AddressSpace, bytes: &[u8]| -> Result<u64, MapError> {
+        sp -= bytes.len() as u64;
+        space.write(VirtAddr::new(sp), bytes)?;
+        Ok(sp)
+    };
+    let mut envp_ptrs = Vec::with_capacity(envp.len());
+    let mut argv_ptrs = Vec::with_capacity(argv.len());
+    for (strs, ptrs) in [(envp, &mut envp_ptrs), (argv, &mut argv_ptrs)] {
+        for s in strs.iter() {
+            push_bytes(space, &[0])?;
+            ptrs.push(push_bytes(space, s.as_bytes())?);
+        }
+    }
+    let random = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let random_ptr = push_bytes(space, &[random.to_le_bytes(), random.rotate_left(32).to_le_bytes()].concat())?;
+
+    let mut words = Vec::with_capacity(words);
+    words.push(argv_ptrs.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    if let Some(phdr) = elf.phdr_addr() {
+        words.extend_from_slice(&[AT_PHDR, phdr]);
+    }
+    words.extend_from_slice(&[
+        AT_PHENT, elf.phentsize() as u64,
+        AT_PHNUM, elf.phnum() as u64,
+        AT_PAGESZ, Size4KiB::SIZE,
+        AT_ENTRY, elf.entry,
+        AT_RANDOM, random_ptr,
+        AT_NULL, 0,
+    ]);
+
+    let sp = (sp - (words.len() * 8) as u64) & !0xf;
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    space.write(VirtAddr::new(sp), &bytes)?;
+    Ok(VirtAddr::new(sp))
+}
+
+
+
+/// 运行嵌入的hello程序，退出码为argc
+#[test_case]
+fn test_load_hello() {
+    let image = find_program("hello").expect("hello not embedded");
+    let handle = spawn("hello", image, &["hello", "lnos"], &["TERM=vga"]).expect("invalid ELF");
+    assert_eq!(handle.join(), Some(2));
+}
+
+/// 不合法的ELF文件
+#[test_case]
+fn test_load_invalid() {
+    assert_eq!(spawn("bad", &[0; 16], &[], &[]).err(), Some(LoadError::Elf(ElfError::TooShort)));
+    assert_eq!(spawn("bad", &[0; 64], &[], &[]).err(), Some(LoadError::Elf(ElfError::BadMagic)));
+
+    // 修改hello的第二个PT_LOAD段的虚拟地址（Program Header表位于偏移64处，每项56字节）
+    let hello = find_program("hello").expect("hello not embedded");
+    let with_vaddr = |vaddr: u64| {
+        let mut image = hello.to_vec();
+        let ofs = 64 + 56 + 16;
+        image[ofs .. ofs + 8].copy_from_slice(&vaddr.to_le_bytes());
+        image
+    };
+    // 段位于内核空间
+    let image = with_vaddr(0xffff_8000_0000_0000);
+    assert_eq!(spawn("bad", &image, &[], &[]).err(), Some(LoadError::Elf(ElfError::BadSegment)));
+    // 段与第一个段重叠
+    let first = ElfFile::parse(hello, user_range()).unwrap().program_headers().next().unwrap().vaddr;
+    let image = with_vaddr(first);
+    assert_eq!(spawn("bad", &image, &[], &[]).err(), Some(LoadError::Map(MapError::AlreadyMapped)));
+
+    // 修改程序入口（ELF Header中偏移24处）
+    let with_entry = |entry: u64| {
+        let mut image = hello.to_vec();
+        image[24 .. 32].copy_from_slice(&entry.to_le_bytes());
+        image
+    };
+    // 入口不是canonical地址
+    let image = with_entry(0x8000_0000_0000_0000);
+    assert_eq!(spawn("bad", &image, &[], &[]).err(), Some(LoadError::Elf(ElfError::BadEntry)));
+    // 入口位于用户空间，但不在任何PT_LOAD段中
+    let image = with_entry(user_range().end - 1);
+    assert_eq!(spawn("bad", &image, &[], &[]).err(), Some(LoadError::Elf(ElfError::BadEntry)));
+}
@@ -0,0 +1,67 @@
+//! 系统调用模块
+//!
+//! 系统调用号与Linux x86_64保持一致，便于直接运行为Linux编译的简单静态程序；
+//! 已分配的系统调用号不再修改。
+//!
+//! 系统调用返回非负数表示成功，返回负的错误码表示失败。
+
+use core::{slice, str};
+use crate::arch::usermode::check_user_range;
+
+
+/// 系统调用号
+pub const SYS_WRITE: usize = 1;
+pub const SYS_SCHED_YIELD: usize = 24;
+pub const SYS_GETPID: usize = 39;
+pub const SYS_EXIT: usize = 60;
+
+/// 错误码
+pub const EBADF: isize = 9;
+pub const EFAULT: isize = 14;
+pub const ENOSYS: isize = 38;
+
+/// 系统调用分发
+pub fn dispatch(num: usize, args: [usize; 6]) -> isize {
+    match num {
+        SYS_WRITE => sys_write(args[0], args[1], args[2]),
+        SYS_SCHED_YIELD => sys_sched_yield(),
+        SYS_GETPID => sys_getpid(),
+        SYS_EXIT => sys_exit(args[0] as i32),
+        _ => {
+            println!("WARNING: unknown syscall {}", num);
+            -ENOSYS
+        },
+    }
+}
+
+/// 向标准输出（1）或标准错误（2）写入数据
+fn sys_write(fd: usize, buf: usize, len: usize) -> isize {
+    if fd != 1 && fd != 2 {
+        return -EBADF;
+    }
+    if !check_user_range(buf, len, false) {
+        return -EFAULT;
+    }
+    let bytes = unsafe { slice::from_raw_parts(buf as *const u8, len) };
+    match str::from_utf8(bytes) {
+        Ok(s) => print!("{}", s),
+        Err(_) => bytes.iter().for_each(|&b| print!("{}", b as char)),
+    }
+    len as isize
+}
+
+/// 让出CPU
+fn sys_sched_yield() -> isize {
+    crate::kthread::yield_now();
+    0
+}
+
+/// 当前线程的id
+fn sys_getpid() -> isize {
+    crate::kthread::current().id().as_u64() as isize
+}
+
+/// 结束当前线程，code作为线程的返回值（JoinHandle<i32>）
+fn sys_exit(code: i32) -> ! {
+    crate::kthread::exit_with(code);
+}
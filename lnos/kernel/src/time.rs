@@ -0,0 +1,336 @@
+//! 系统时钟模块
+//!
+//! Timer中断以HZ的频率触发，每次中断ticks加1，作为单调递增的系统时钟；
+//! 同时检查cotask的时间轮，唤醒到期的task。
+//!
+//! ticks的精度只有1/HZ秒，需要更高精度时使用Instant，Instant从ClockSource读取时间：
+//! - 各个时钟源（TSC、HPET、PIT等）初始化后通过register_clock_source注册；
+//! - 使用rating最高的时钟源，切换时钟源时保证时间连续且单调递增。
+//!
+//! 日历时间（SystemTime）为启动时间（由RTC驱动通过set_boot_time设置）加上Instant，
+//! 即启动后不再读取RTC，修改RTC不影响SystemTime。
+
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+
+
+/// Timer中断的频率
+pub const HZ: u64 = 100;
+
+/// 每个tick的纳秒数
+pub const NANOS_PER_TICK: u64 = 1_000_000_000 / HZ;
+
+/// 启动以来的tick数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// 启动以来的tick数
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 启动以来的纳秒数（精度为一个tick）
+pub fn nanos() -> u64 {
+    ticks() * NANOS_PER_TICK
+}
+
+/// 启动以来的时间
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanos())
+}
+
+/// 时间对应的tick数（向上取整）
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos();
+    ((nanos + NANOS_PER_TICK as u128 - 1) / NANOS_PER_TICK as u128) as u64
+}
+
+/// tick数对应的时间
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * NANOS_PER_TICK)
+}
+
+/// Timer中断调用：更新tick，唤醒到期的task
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::cotask::timer::expire(now);
+}
+
+
+/// 时钟源，计数值以固定的频率单调递增
+pub trait ClockSource: Sync {
+    /// 时钟源名称
+    fn name(&self) -> &'static str;
+    /// 当前计数值
+    fn read(&self) -> u64;
+    /// 计数频率（Hz）
+    fn frequency(&self) -> u64;
+    /// 时钟源的优先级，优先使用rating高的时钟源
+    fn rating(&self) -> u32;
+}
+
+/// 当前使用的时钟源，以及切换到该时钟源时的计数值和纳秒数
+struct Clock {
+    source: &'static dyn ClockSource,
+    base_cycles: u64,
+    base_nanos: u64,
+}
+
+impl Clock {
+    fn nanos(&self) -> u64 {
+        let cycles = self.source.read().wrapping_sub(self.base_cycles);
+        self.base_nanos + (cycles as u128 * 1_000_000_000 / self.source.frequency() as u128) as u64
+    }
+}
+
+/// 时钟源只能在关中断时修改（Timer中断中也会读取）
+static CLOCK: RwLock<Option<Clock>> = RwLock::new(None);
+
+/// 返回过的最大纳秒数，保证Instant单调递增
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// 注册时钟源，若rating高于当前的时钟源则切换到该时钟源，返回是否切换
+pub fn register_clock_source(source: &'static dyn ClockSource) -> bool {
+    assert!(source.frequency() != 0, "clock source {} has zero frequency", source.name());
+    interrupts::without_interrupts(|| {
+        let mut clock = CLOCK.write();
+        if let Some(current) = clock.as_ref() {
+            if current.source.rating() >= source.rating() {
+                return false;
+            }
+        }
+        // 新时钟源从当前时间开始计时
+        let base_nanos = clock.as_ref().map_or_else(nanos, Clock::nanos).max(LAST_NANOS.load(Ordering::Relaxed));
+        *clock = Some(Clock { source, base_cycles: source.read(), base_nanos });
+        println!("Clock Source: {} ({} Hz)", source.name(), source.frequency());
+        true
+    })
+}
+
+/// 当前使用的时钟源名称
+pub fn clock_source_name() -> Option<&'static str> {
+    CLOCK.read().as_ref().map(|c| c.source.name())
+}
+
+/// 当前使用的时钟源的rating
+pub fn clock_source_rating() -> Option<u32> {
+    CLOCK.read().as_ref().map(|c| c.source.rating())
+}
+
+/// 单调递增的时间点，精度由时钟源决定（使用TSC时为纳秒级）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// 当前时间；未注册时钟源时使用ticks
+    pub fn now() -> Instant {
+        let nanos = CLOCK.read().as_ref().map_or_else(nanos, Clock::nanos);
+        let last = LAST_NANOS.fetch_max(nanos, Ordering::Relaxed);
+        Instant(nanos.max(last))
+    }
+
+    /// 启动以来的纳秒数
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// 从earlier到self经过的时间，earlier更晚时返回0
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// 从self到现在经过的时间
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0 - rhs.as_nanos() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+
+/// 启动时（Instant为0时）的Unix时间（纳秒），未设置时为0
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// 设置启动时的Unix时间：now为当前的日历时间
+pub fn set_boot_time(now: DateTime) {
+    let nanos = now.to_unix() * 1_000_000_000;
+    BOOT_TIME.store(nanos.saturating_sub(Instant::now().as_nanos()), Ordering::Relaxed);
+}
+
+/// 日历时间（UTC），以Unix时间（1970-01-01 00:00:00以来的纳秒数）表示
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+    /// 当前的日历时间；未设置启动时间时从1970-01-01开始
+    pub fn now() -> SystemTime {
+        SystemTime(BOOT_TIME.load(Ordering::Relaxed) + Instant::now().as_nanos())
+    }
+
+    pub fn from_unix(secs: u64) -> SystemTime {
+        SystemTime(secs * 1_000_000_000)
+    }
+
+    /// Unix时间（秒）
+    pub fn as_unix(&self) -> u64 {
+        self.0 / 1_000_000_000
+    }
+
+    /// 从earlier到self经过的时间，earlier更晚时返回None
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn to_datetime(&self) -> DateTime {
+        DateTime::from_unix(self.as_unix())
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_datetime().fmt(f)
+    }
+}
+
+/// 日期和时间（UTC）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1~12
+    pub month: u8,
+    /// 1~31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// 转换为Unix时间（秒），早于1970年时返回0（如RTC的值不正确）
+    ///
+    /// 日期转换为天数的算法见<http://howardhinnant.github.io/date_algorithms.html>（days_from_civil）。
+    pub fn to_unix(&self) -> u64 {
+        let (month, day) = (self.month as i64, self.day as i64);
+        // 以3月为一年的开始，闰日位于一年的最后（year为0时，1~2月属于上一年，所以使用有符号数）
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        let secs = days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        secs.max(0) as u64
+    }
+
+    /// 由Unix时间（秒）转换（civil_from_days）
+    pub fn from_unix(secs: u64) -> DateTime {
+        let days = secs / 86400 + 719468;
+        let era = days / 146097;
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+        let rem = secs % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+
+/// Instant单调递增，且与ticks一致
+#[test_case]
+fn test_instant() {
+    let start = Instant::now();
+    let mut last = start;
+    for _ in 0 .. 1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // 等待2个tick（需要开中断）
+    let ticks = ticks();
+    let begin = Instant::now();
+    interrupts::enable();
+    while self::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    interrupts::disable();
+    let elapsed = begin.elapsed();
+    // 最多误差1个tick
+    assert!(elapsed >= ticks_to_duration(1), "{:?}", elapsed);
+    assert!(elapsed <= ticks_to_duration(3), "{:?}", elapsed);
+}
+
+
+
+/// 日期与Unix时间相互转换
+#[test_case]
+fn test_datetime() {
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(epoch.to_unix(), 0);
+    let leap = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 45, second: 7 };
+    assert_eq!(leap.to_unix(), 1_709_214_307);
+    assert_eq!(DateTime::from_unix(1_709_214_307), leap);
+    assert_eq!(DateTime::from_unix(951_782_400).day, 29); // 2000-02-29
+    let before = DateTime { year: 1969, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+    assert_eq!(before.to_unix(), 0);
+    assert_eq!(DateTime { year: 0, month: 1, ..epoch }.to_unix(), 0);
+
+    let boot = SystemTime::now();
+    assert!(SystemTime::now().duration_since(boot).is_some());
+}